@@ -0,0 +1,240 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io::{self, prelude::*};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// a cookie that, when set for both a genie and a client, lets the client make
+// privileged requests without reading the genie's token file
+pub const ADMIN_COOKIE_VAR: &str = "GENIE_ADMIN_COOKIE";
+
+// how long a genie gets to answer an exit request
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long genied gets to stop a genie and have another listening in its place
+pub const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how a genie answering a move request, or genied a restart request, starts
+// the line saying why it couldn't; otherwise it answers with the new socket
+// path
+pub const MOVE_FAILED: &str = "error: ";
+
+// what a genie answers to a privileged request it won't honour
+pub const REFUSED: &[u8] = b"unauthorized\n";
+
+// how the requests that need a credential start
+pub const PRIVILEGED: [&[u8]; 3] = [b"exit\n", b"move\n", b"restart\n"];
+
+// The token or privileged cookie sent along with a privileged request like
+// exit. Kept out of logs.
+pub struct Credential(pub String);
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Credential(..)")
+    }
+}
+
+// socket_path with its extension swapped, e.g. watch.1a2b.token
+pub fn token_path(socket_path: &Path) -> PathBuf {
+    socket_path.with_extension("token")
+}
+
+// What a genie will accept as a credential: the token it wrote next to its
+// socket, readable only by its owner, and the privileged cookie if it was
+// given one.
+pub struct Auth {
+    token: String,
+    admin_cookie: Option<String>,
+}
+
+impl Auth {
+    // Makes up a token and writes it next to socket_path. The caller is
+    // responsible for removing the file on the way out.
+    pub fn create(socket_path: &Path) -> io::Result<Auth> {
+        let token = random()?;
+
+        // left over from some earlier genie with our pid
+        let path = token_path(socket_path);
+        let _ = std::fs::remove_file(&path);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        writeln!(file, "{}", token)?;
+
+        let admin_cookie = std::env::var(ADMIN_COOKIE_VAR)
+            .ok()
+            .filter(|cookie| !cookie.is_empty());
+
+        Ok(Auth {
+            token,
+            admin_cookie,
+        })
+    }
+
+    pub fn allows(&self, credential: &Credential) -> bool {
+        same(&credential.0, &self.token)
+            || self
+                .admin_cookie
+                .as_ref()
+                .is_some_and(|cookie| same(&credential.0, cookie))
+    }
+}
+
+// 128 random bits in hex, good for a token or a cookie
+pub fn random() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Compares without giving away how much of a guess was right, or how long
+// the right answer is: what's compared is a digest of each, of the same
+// length whatever theirs, keyed afresh so neither can be worked out. Digests
+// that match do so by chance only one time in 2^64, which comparing the
+// strings themselves then rules out.
+pub(crate) fn same(a: &str, b: &str) -> bool {
+    let key = RandomState::new();
+    key.hash_one(a) == key.hash_one(b) && a == b
+}
+
+// The credential to send to the genie at socket_path: its token, if we can
+// read it, and the privileged cookie otherwise.
+pub fn credential(socket_path: &Path) -> io::Result<Credential> {
+    match std::fs::read_to_string(token_path(socket_path)) {
+        Ok(token) => Ok(Credential(token.trim().to_string())),
+        Err(err) => match std::env::var(ADMIN_COOKIE_VAR) {
+            Ok(cookie) if !cookie.is_empty() => Ok(Credential(cookie)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "unable to read {} ({}) and {} is not set",
+                    token_path(socket_path).display(),
+                    err,
+                    ADMIN_COOKIE_VAR
+                ),
+            )),
+        },
+    }
+}
+
+// Asks the genie at socket_path to exit.
+pub fn exit(socket_path: &Path) -> io::Result<()> {
+    exit_within(socket_path, EXIT_TIMEOUT)
+}
+
+// Asks the genie at socket_path to exit, giving up if it doesn't answer
+// within timeout.
+pub fn exit_within(socket_path: &Path, timeout: Duration) -> io::Result<()> {
+    let credential = credential(socket_path)?;
+    let response =
+        crate::request_within(socket_path, &format!("exit\n{}\n", credential.0), timeout)?;
+    if response == REFUSED {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} refused to exit", socket_path.display()),
+        ));
+    }
+    Ok(())
+}
+
+// Copies the token beside the entry at from to beside the entry at to, for
+// an entry that can't simply be renamed there.
+pub fn copy_token(from: &Path, to: &Path) -> io::Result<()> {
+    let token = std::fs::read(token_path(from))?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(token_path(to))?;
+    file.write_all(&token)
+}
+
+// Asks the genie at socket_path to bind a new socket in dir and drop the old
+// one, returning the new socket's path.
+pub fn move_to(socket_path: &Path, dir: &str) -> io::Result<PathBuf> {
+    let credential = credential(socket_path)?;
+    let response = crate::request_within(
+        socket_path,
+        &format!("move\n{}\n{}\n", credential.0, dir),
+        EXIT_TIMEOUT,
+    )?;
+    new_socket(socket_path, &response, "move")
+}
+
+// Asks genied, at socket_path, to restart the genie it runs with pid (in hex,
+// as in its socket name), returning the socket of the one started in its
+// place once that's listening.
+pub fn restart(socket_path: &Path, pid: &str) -> io::Result<PathBuf> {
+    let credential = credential(socket_path)?;
+    let response = crate::request_within(
+        socket_path,
+        &format!("restart\n{}\n{}\n", credential.0, pid),
+        RESTART_TIMEOUT,
+    )?;
+    new_socket(socket_path, &response, "restart genies")
+}
+
+// the new socket path in a genie's answer to a request to do what
+fn new_socket(socket_path: &Path, response: &[u8], what: &str) -> io::Result<PathBuf> {
+    if response == REFUSED {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} refused to {}", socket_path.display(), what),
+        ));
+    }
+
+    let response = String::from_utf8_lossy(response);
+    let response = response.trim_end();
+    if response.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} doesn't know how to {}", socket_path.display(), what),
+        ));
+    }
+    match response.strip_prefix(MOVE_FAILED) {
+        Some(err) => Err(io::Error::other(err.to_string())),
+        None => Ok(PathBuf::from(response)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_whole_strings() {
+        assert!(same("0123abcd", "0123abcd"));
+        assert!(same("", ""));
+        assert!(!same("0123abcd", "0123abce"));
+        assert!(!same("0123abcd", "0123abc"));
+        assert!(!same("0123abc", "0123abcd"));
+        assert!(!same("", "0123abcd"));
+    }
+
+    #[test]
+    fn allows_the_token_and_the_admin_cookie_only() {
+        let credential = |credential: &str| Credential(credential.to_string());
+        let auth = Auth {
+            token: "0123abcd".to_string(),
+            admin_cookie: None,
+        };
+        assert!(auth.allows(&credential("0123abcd")));
+        for refused in ["", "0123abc", "0123abcde", "admin"] {
+            assert!(!auth.allows(&credential(refused)), "{:?}", refused);
+        }
+
+        let auth = Auth {
+            admin_cookie: Some("admin".to_string()),
+            ..auth
+        };
+        assert!(auth.allows(&credential("0123abcd")));
+        assert!(auth.allows(&credential("admin")));
+        assert!(!auth.allows(&credential("")));
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    std::process::exit(genie::cli::main(std::env::args_os()))
+}
@@ -0,0 +1,53 @@
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+// genie_activate <socket> <genie> [args...]
+//
+// Binds socket and waits for the first client to connect, then runs the
+// genie with the listening socket passed as systemd would pass it.
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (socket, genie) = match (args.next(), args.next()) {
+        (Some(socket), Some(genie)) => (socket, genie),
+        _ => {
+            eprintln!("usage: genie_activate <socket> <genie> [args...]");
+            std::process::exit(2);
+        }
+    };
+
+    let listener = UnixListener::bind(&socket).expect("failed to bind socket");
+    std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600))
+        .expect("failed to make socket private");
+
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            panic!("error waiting for a connection: {}", err);
+        }
+    }
+
+    // the listener may already be the descriptor we want, in which case
+    // dup2 does nothing, so clear close-on-exec by hand either way
+    let fd = genie::LISTEN_FDS_START;
+    if unsafe { libc::dup2(listener.as_raw_fd(), fd) } < 0
+        || unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0
+    {
+        panic!("unable to pass socket: {}", std::io::Error::last_os_error());
+    }
+
+    let err = Command::new(&genie)
+        .args(args)
+        .env("LISTEN_FDS", "1")
+        .env("LISTEN_PID", std::process::id().to_string())
+        .exec();
+    eprintln!("unable to run {}: {}", genie, err);
+    std::process::exit(1);
+}
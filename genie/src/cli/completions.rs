@@ -0,0 +1,125 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use clap::{ArgMatches, Shell};
+
+use super::init::{quote_fish, quote_sh, this_genie};
+
+// how long a genie gets to say its label while someone waits at a tab
+const LABEL_TIMEOUT: Duration = Duration::from_millis(250);
+
+// the subcommands whose arguments are genies
+const TAKING_GENIES: [&str; 7] = [
+    "get", "promote", "demote", "move", "send", "kill", "restart",
+];
+
+// Added to what clap generates, which completes subcommands and options but
+// knows nothing of the genies running. @GENIE@ is this program, which the
+// shell calls back to list them.
+
+const BASH: &str = r#"
+__genie_genies() {
+    @GENIE@ complete 2>/dev/null
+}
+
+__genie_promote() {
+    COMPREPLY=( $(compgen -W "$(__genie_genies)" -- "${COMP_WORDS[COMP_CWORD]}") )
+}
+
+complete -F __genie_promote genie_promote
+"#;
+
+const ZSH: &str = r#"
+__genie_genies() {
+    local -a genies
+    genies=(${(f)"$(@GENIE@ complete 2>/dev/null)"})
+    compadd -a genies
+}
+
+compdef __genie_genies genie_promote
+"#;
+
+const FISH: &str = r#"
+complete -c genie -n "__fish_seen_subcommand_from @TAKING@" -f -a "(@GENIE@ complete)"
+complete -c genie -n "__fish_seen_subcommand_from move" -f -a "first last"
+complete -c genie_promote -f -a "(@GENIE@ complete)"
+"#;
+
+// Prints a script that completes genie's subcommands and options, and the
+// names, name.pids and labels of the genies along GENIE_PATH wherever a
+// subcommand takes a genie.
+pub fn run(matches: &ArgMatches) -> bool {
+    let shell = matches.value_of("shell").unwrap();
+    let mut generated = Vec::new();
+    super::app().gen_completions_to("genie", shell.parse::<Shell>().unwrap(), &mut generated);
+    let generated = String::from_utf8_lossy(&generated);
+
+    // clap puts each positional argument's name where its values go
+    let script = match shell {
+        "fish" => format!(
+            "{}{}",
+            generated,
+            FISH.replace("@TAKING@", &TAKING_GENIES.join(" "))
+                .replace("@GENIE@", &quote_fish(&this_genie()))
+        ),
+        "zsh" => format!(
+            "{}{}",
+            generated
+                .lines()
+                .map(|line| match line {
+                    _ if line.starts_with("':genie -- ") || line.starts_with("'::genie -- ") => {
+                        line.replace(":_files'", ":__genie_genies'")
+                    }
+                    _ if line.starts_with("':level -- ") => {
+                        line.replace(":_files'", ":(first last)'")
+                    }
+                    // it's evaluated, not autoloaded
+                    "_genie \"$@\"" => "compdef _genie genie".to_string(),
+                    _ => line.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ZSH.replace("@GENIE@", &quote_sh(&this_genie()))
+        ),
+        _ => format!(
+            "{}{}",
+            generated
+                .replace("<genie>...", "$(__genie_genies)")
+                .replace("<genie>", "$(__genie_genies)")
+                .replace("<level>", "first last"),
+            BASH.replace("@GENIE@", &quote_sh(&this_genie()))
+        ),
+    };
+    print!("{}", script);
+    true
+}
+
+// Lists, one to a line, what could stand for each genie along the path and
+// starts with prefix, for completion scripts to call back. Labels are left
+// out for genies that don't give them quickly.
+pub fn complete(matches: &ArgMatches) -> bool {
+    let path = match std::env::var("GENIE_PATH") {
+        Ok(path) => path,
+        Err(_) => return true,
+    };
+    let prefix = matches.value_of("prefix").unwrap_or_default();
+
+    let mut seen = HashSet::new();
+    for entry in crate::entries(&path) {
+        let label = crate::request_within(&entry.path, "info\n", LABEL_TIMEOUT)
+            .and_then(|response| crate::info::Info::parse(&response))
+            .ok()
+            .and_then(|info| info.label);
+        let candidates = [
+            Some(entry.name.clone()),
+            Some(format!("{}.{}", entry.name, entry.pid)),
+            label,
+        ];
+        for candidate in candidates.iter().flatten() {
+            if candidate.starts_with(prefix) && seen.insert(candidate.clone()) {
+                println!("{}", candidate);
+            }
+        }
+    }
+    true
+}
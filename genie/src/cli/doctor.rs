@@ -0,0 +1,301 @@
+use std::ffi::CString;
+use std::fmt::Display;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::init::HOOK_VAR;
+use super::kill::timed_out;
+use crate::cookie::COOKIE_VAR;
+
+// What we found, a line at a time, and whether any of it stops genies from
+// reaching us.
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn ok(&self, what: impl Display) {
+        println!("ok    {}", what);
+    }
+
+    // works, but likely not as meant
+    fn warn(&self, what: impl Display, fix: impl Display) {
+        println!("warn  {}", what);
+        println!("      fix: {}", fix);
+    }
+
+    fn fail(&mut self, what: impl Display, fix: impl Display) {
+        println!("FAIL  {}", what);
+        println!("      fix: {}", fix);
+        self.failed = true;
+    }
+}
+
+// Checks what notifications depend on, the cookie, GENIE_PATH, every genie
+// along it and the prompt hook, and says how to fix whatever is wrong. Needs
+// nothing to be set up, so runs before Config.
+pub fn run() -> bool {
+    let mut report = Report { failed: false };
+
+    check_cookie(&mut report);
+    match std::env::var("GENIE_PATH") {
+        Ok(path) if !path.is_empty() => {
+            report.ok(format!("GENIE_PATH is {}", path));
+            for (level, entry) in crate::split_path(&path).iter().enumerate() {
+                if crate::tcp::is_tcp(entry) {
+                    check_tcp(&mut report, entry);
+                } else {
+                    check_dir(&mut report, level, entry);
+                }
+            }
+            check_genies(&mut report, &path);
+        }
+        _ => report.fail(
+            "GENIE_PATH is not set, so genies have nowhere to go",
+            "export GENIE_PATH=\"$XDG_RUNTIME_DIR/genie\" in your shell's startup file, \
+             and mkdir -m 700 that directory",
+        ),
+    }
+    check_hook(&report);
+
+    !report.failed
+}
+
+fn check_cookie(report: &mut Report) {
+    let terminal = crate::cookie::for_terminal();
+    match std::env::var(COOKIE_VAR) {
+        Ok(cookie) if cookie.is_empty() || !cookie.bytes().all(|b| b.is_ascii_alphanumeric()) => {
+            report.fail(
+                format!("{} is {:?}, which genies won't accept", COOKIE_VAR, cookie),
+                format!("export {}=$(genie cookie)", COOKIE_VAR),
+            )
+        }
+        // panes all start with the environment tmux did
+        Ok(cookie) if std::env::var_os("TMUX_PANE").is_some() && terminal != Some(cookie.clone()) => {
+            report.warn(
+                format!(
+                    "{} is {}, which other tmux panes may share, each taking the others' news",
+                    COOKIE_VAR, cookie
+                ),
+                format!(
+                    "eval \"$(genie init {})\" in your shell's startup file, or export {}=$(genie cookie)",
+                    shell(),
+                    COOKIE_VAR
+                ),
+            )
+        }
+        Ok(cookie) => report.ok(format!("{} is {}", COOKIE_VAR, cookie)),
+        Err(_) => match terminal {
+            Some(cookie) => report.ok(format!(
+                "{} is not set, so this terminal's, {}, is used",
+                COOKIE_VAR, cookie
+            )),
+            None => report.fail(
+                format!(
+                    "{} is not set, and there's no terminal to make one up for",
+                    COOKIE_VAR
+                ),
+                format!("export {}=$(genie cookie --random)", COOKIE_VAR),
+            ),
+        },
+    }
+}
+
+// Genies bind in the first directory, and are promoted into the rest, so
+// each must be there, ours, or shared with a group we're in and trust, and
+// writable.
+fn check_dir(report: &mut Report, level: usize, dir: &str) {
+    let path = Path::new(dir);
+    let what = format!("level {}, {}", level, dir);
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let fix = format!("mkdir -m 700 {}", dir);
+            let problem = format!("{} does not exist", what);
+            return if level == 0 {
+                report.fail(problem, fix)
+            } else {
+                report.warn(problem, fix)
+            };
+        }
+        Err(err) => {
+            return report.fail(
+                format!("{}: {}", what, err),
+                format!("make sure you can get to {}", dir),
+            )
+        }
+    };
+    if !meta.is_dir() {
+        return report.fail(
+            format!("{} is not a directory", what),
+            format!("take {} out of GENIE_PATH, or make it a directory", dir),
+        );
+    }
+
+    match crate::perms::check_owner(path) {
+        Ok(()) => {}
+        // one we can't write to as a member isn't shared, just someone else's
+        Err(_)
+            if meta.mode() & 0o020 != 0
+                && crate::perms::check_shared_dir(path, meta.gid()).is_ok() =>
+        {
+            if !crate::perms::trusts_group(meta.gid()) {
+                return report.fail(
+                    format!(
+                        "{} is shared with gid {}, which {} doesn't list, so others' genies there are refused",
+                        what,
+                        meta.gid(),
+                        crate::perms::SHARED_GROUPS_VAR
+                    ),
+                    format!(
+                        "export {}={} if you trust everyone in it",
+                        crate::perms::SHARED_GROUPS_VAR,
+                        meta.gid()
+                    ),
+                );
+            }
+        }
+        Err(err) => {
+            return report.fail(
+                err,
+                format!(
+                    "use a directory of your own, or chown $USER {} && chmod 700 {}",
+                    dir, dir
+                ),
+            )
+        }
+    }
+    if !writable(path) {
+        return report.fail(
+            format!("{} is not writable, so genies can't bind there", what),
+            format!("chmod u+w {}", dir),
+        );
+    }
+    if meta.mode() & 0o002 != 0 {
+        return report.warn(
+            format!("{} is writable by anyone", what),
+            format!("chmod o-w {}", dir),
+        );
+    }
+    report.ok(what);
+}
+
+fn writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+// A tcp entry needs the token its genie was started with, and to answer.
+fn check_tcp(report: &mut Report, entry: &str) {
+    if crate::tcp::token().is_err() {
+        return report.fail(
+            format!(
+                "{} needs {}, which is not set",
+                entry,
+                crate::tcp::TOKEN_VAR
+            ),
+            format!(
+                "export {}=<the token the genie there was started with>",
+                crate::tcp::TOKEN_VAR
+            ),
+        );
+    }
+
+    match crate::tcp::request_within(entry, "info\n", crate::QUERY_TIMEOUT)
+        .and_then(|response| crate::info::Info::parse(&response))
+    {
+        Ok(info) => report.ok(format!("{} answers, as {}", entry, info.name)),
+        Err(err) if timed_out(&err) => report.fail(
+            format!("{} did not answer within {:?}", entry, crate::QUERY_TIMEOUT),
+            "check the host is up and nothing between drops the connection",
+        ),
+        Err(err) => report.fail(
+            format!("{}: {}", entry, err),
+            format!(
+                "check a genie is listening there, and that {} matches its token",
+                crate::tcp::TOKEN_VAR
+            ),
+        ),
+    }
+}
+
+// Asks every genie along the path to describe itself, giving up on those
+// that take too long.
+fn check_genies(report: &mut Report, path: &str) {
+    let entries = crate::entries(path);
+    if entries.is_empty() {
+        println!("      no genies along GENIE_PATH");
+        return;
+    }
+
+    for entry in entries {
+        let spec = format!("{}.{}", entry.name, entry.pid);
+        match crate::info::query(&entry.path) {
+            Ok(_) => report.ok(format!("{} answers", spec)),
+            Err(err) if timed_out(&err) => report.fail(
+                format!(
+                    "{} is hung, not answering within {:?}",
+                    spec,
+                    crate::QUERY_TIMEOUT
+                ),
+                format!(
+                    "genie kill {}, which signals it if need be, or genie restart {}",
+                    spec, spec
+                ),
+            ),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => report.warn(
+                format!("{} is dead, leaving a stale {}", spec, entry.path.display()),
+                "genie gc",
+            ),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => report.warn(
+                err,
+                format!("remove {} if it isn't yours to trust", entry.path.display()),
+            ),
+            Err(err) => report.fail(
+                format!("{}: {}", spec, err),
+                format!("genie kill {}, then start it again", spec),
+            ),
+        }
+    }
+}
+
+// The snippets genie init prints say they've run, and in which shell: one
+// they ran in passes that on to whatever it starts, which polls nothing.
+// A hook put in by hand doesn't say, which we can only point out.
+fn check_hook(report: &Report) {
+    let fix = match shell().as_str() {
+        "fish" => "add `genie init fish | source` to ~/.config/fish/config.fish".to_string(),
+        shell => format!("add `eval \"$(genie init {})\"` to ~/.{}rc", shell, shell),
+    };
+    let hook = std::env::var(HOOK_VAR).unwrap_or_default();
+    match hook.rsplit_once('.') {
+        Some((shell, pid)) if pid.parse() == Ok(unsafe { libc::getppid() }) => {
+            report.ok(format!("the {} prompt polls genies", shell))
+        }
+        Some((shell, pid)) => report.warn(
+            format!(
+                "the {} prompt hook was set up by pid {}, not the shell we were run from, \
+                 which may not poll genies",
+                shell, pid
+            ),
+            fix,
+        ),
+        None => report.warn(
+            "no prompt hook, so nothing polls genies (or one set up by hand that we can't see)",
+            fix,
+        ),
+    }
+}
+
+// the user's shell, for fixes to mention; bash unless it's one we know
+fn shell() -> String {
+    let shell = std::env::var("SHELL").unwrap_or_default();
+    match shell.rsplit('/').next() {
+        Some(name @ ("zsh" | "fish")) => name.to_string(),
+        _ => "bash".to_string(),
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+
+use super::{supervised, warn, Config};
+
+// Tells every genie along the path that we won't be polling again, so it can
+// drop what it kept for our cookie, as a shell does on its way out. Genies
+// run by genied hear it through genied. Those on other hosts are left alone,
+// rather than hold up the shell if they can't be reached.
+pub fn run(config: &Config) -> bool {
+    let cookie = match config.cookie() {
+        Ok(cookie) => cookie,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    for entry in crate::entries(&config.path) {
+        if supervised(&entry) {
+            continue;
+        }
+
+        let request = format!("forget\n{}\n", cookie);
+        if let Err(err) = crate::request_within(&entry.path, &request, crate::QUERY_TIMEOUT) {
+            // one already gone took what it kept with it
+            let gone = matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+            );
+            if !gone {
+                warn(format!("{}.{}: {}", entry.name, entry.pid, err));
+                ok = false;
+            }
+        }
+    }
+    ok
+}
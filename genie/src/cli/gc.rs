@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+
+use super::{warn, Config};
+
+// Removes, from each directory along the path (and those genied keeps for
+// its genies), the sockets of genies that are gone, their tokens, the
+// directories of genieds that are gone, singleton locks nobody holds and,
+// with --logs, their logs. Only our own files are touched.
+pub fn run(config: &Config, matches: &ArgMatches) -> bool {
+    let logs = matches.is_present("logs");
+    let mut ok = true;
+
+    let dirs = crate::split_path(&config.path)
+        .into_iter()
+        .filter(|dir| !crate::tcp::is_tcp(dir))
+        .collect::<Vec<_>>();
+
+    // sockets first, so that what they leave behind goes with them
+    for dir in &dirs {
+        ok &= report(dir, collect_sockets(Path::new(dir)));
+    }
+
+    // a promoted genie's token and log aren't beside its socket
+    let live = crate::entries(&config.path)
+        .into_iter()
+        .map(|entry| format!("{}.{}", entry.name, entry.pid))
+        .collect::<HashSet<_>>();
+
+    for dir in &dirs {
+        ok &= report(dir, collect(Path::new(dir), &live, logs));
+    }
+
+    ok
+}
+
+fn report(dir: &str, result: io::Result<()>) -> bool {
+    match result {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            warn(format!("{}: {}", dir, err));
+            false
+        }
+        _ => true,
+    }
+}
+
+fn ours(path: &Path) -> bool {
+    crate::perms::insecure()
+        || std::fs::symlink_metadata(path).is_ok_and(|meta| meta.uid() == crate::perms::uid())
+}
+
+fn remove(path: &Path) {
+    let removed = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+
+    match removed {
+        Ok(()) => println!("removed {}", path.display()),
+        Err(err) => warn(format!("unable to remove {}: {}", path.display(), err)),
+    }
+}
+
+fn dead(socket_path: &Path) -> bool {
+    match crate::connect(socket_path) {
+        Ok(_) => false,
+        Err(err) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+        ),
+    }
+}
+
+fn paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    Ok(paths)
+}
+
+fn filename(path: &Path) -> &str {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+}
+
+fn collect_sockets(dir: &Path) -> io::Result<()> {
+    let re = regex::Regex::new(crate::SOCKNAME_PATTERN).unwrap();
+
+    for path in &paths(dir)? {
+        let filename = filename(path);
+        if crate::is_supervised_dir(filename) && ours(path) {
+            collect_sockets(path)?;
+        } else if re.is_match(filename) && ours(path) && dead(path) {
+            remove(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Removes a singleton's lock file if nobody holds the lock, holding it
+// ourselves meanwhile so that a genie starting up can tell it's gone.
+fn collect_lock(path: &Path) {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    match crate::try_lock(&file) {
+        Ok(true) => remove(path),
+        Ok(false) => (),
+        Err(err) => warn(format!("unable to lock {}: {}", path.display(), err)),
+    }
+}
+
+fn collect(dir: &Path, live: &HashSet<String>, logs: bool) -> io::Result<()> {
+    for path in &paths(dir)? {
+        let filename = filename(path);
+        if !ours(path) || !path.exists() {
+            continue;
+        }
+
+        if crate::is_supervised_dir(filename) {
+            let pid = filename.split('.').nth(1).unwrap_or_default();
+            let alive = u32::from_str_radix(pid, 16)
+                .is_ok_and(|pid| unsafe { libc::kill(pid as libc::pid_t, 0) } == 0);
+            if alive {
+                collect(path, live, logs)?;
+            } else {
+                remove(path);
+            }
+            continue;
+        }
+
+        if filename.ends_with(".lock") {
+            collect_lock(path);
+            continue;
+        }
+
+        // name.pid.token, name.pid.log, name.pid.log.1, ...
+        let mut parts = filename.splitn(4, '.');
+        let stem = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(pid), Some("token")) => Some((name, pid, false)),
+            (Some(name), Some(pid), Some("log")) => Some((name, pid, true)),
+            _ => None,
+        };
+        if let Some((name, pid, log)) = stem {
+            let orphaned = !live.contains(&format!("{}.{}", name, pid));
+            if orphaned && (!log || logs) {
+                remove(path);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::io::{self, prelude::*};
+
+use clap::ArgMatches;
+
+use super::{resolve, warn, Config};
+
+// the exit status when the genie had nothing to show
+pub const NOTHING: i32 = 2;
+
+// Prints a genie's output, returning the exit status.
+pub fn run(config: &Config, matches: &ArgMatches) -> i32 {
+    match get(config, matches) {
+        Ok(true) => 0,
+        Ok(false) => NOTHING,
+        Err(err) => {
+            warn(err);
+            1
+        }
+    }
+}
+
+// false if there was no output
+fn get(config: &Config, matches: &ArgMatches) -> Result<bool, String> {
+    let spec = matches.value_of("genie").unwrap();
+    let entry = resolve(config, spec)?;
+
+    // what we saw when we last polled, unless we've never polled, in which
+    // case the two are the same
+    let request = if matches.is_present("latest") {
+        "latest\n".to_string()
+    } else {
+        format!("get\n{}\n", config.cookie_for(&entry)?)
+    };
+    let response =
+        crate::request(&entry.path, &request).map_err(|err| format!("{}: {}", spec, err))?;
+
+    if matches.is_present("json") {
+        let json = serde_json::json!({
+            "name": entry.name,
+            "pid": u32::from_str_radix(&entry.pid, 16).ok(),
+            "path": entry.path,
+            "output": String::from_utf8_lossy(&response),
+        });
+        println!("{}", serde_json::to_string_pretty(&json).unwrap());
+    } else {
+        io::stdout()
+            .write_all(&response)
+            .map_err(|err| format!("error writing output: {}", err))?;
+    }
+
+    Ok(!response.is_empty())
+}
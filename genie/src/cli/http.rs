@@ -0,0 +1,357 @@
+use std::io::{self, prelude::*};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use super::poll::{poll_tcp, responses, verb, POLL_TIMEOUT};
+use super::{cookie_for, supervised, warn};
+use crate::info::Info;
+
+const DEFAULT_PORT: u16 = 7077;
+
+// what the cookies the bridge polls with start with; each event stream
+// has one of its own, so that every browser tab, of every bridge, sees every
+// update
+const COOKIE_PREFIX: &str = "http";
+
+const INDEX: &str = r#"<!doctype html>
+<title>genies</title>
+<pre id="out"></pre>
+<script>
+  const out = document.getElementById("out");
+  const events = new EventSource("/events");
+  events.addEventListener("output", (event) => {
+    const { name, pid, output } = JSON.parse(event.data);
+    out.textContent += output.replace(/^/gm, `${name}(${pid}): `) + "\n";
+  });
+</script>
+"#;
+
+#[derive(Serialize)]
+struct Genie {
+    name: String,
+    pid: String,
+    level: usize,
+    path: PathBuf,
+    // None if it didn't answer
+    info: Option<Info>,
+}
+
+#[derive(Serialize)]
+struct Output<'a> {
+    name: &'a str,
+    pid: &'a str,
+    output: String,
+}
+
+fn genies(path: &str) -> Vec<Genie> {
+    let mut genies = crate::entries(path)
+        .into_iter()
+        .map(|entry| Genie {
+            info: crate::info::query(&entry.path).ok(),
+            name: entry.name,
+            pid: entry.pid,
+            level: entry.level,
+            path: entry.path,
+        })
+        .collect::<Vec<_>>();
+
+    // one listening on tcp is known by what it says of itself
+    for (level, entry) in tcp_entries(path) {
+        let info = crate::tcp::request_within(&entry, "info\n", crate::QUERY_TIMEOUT)
+            .and_then(|response| Info::parse(&response))
+            .ok();
+        genies.push(Genie {
+            name: info
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |info| info.name.clone()),
+            pid: info
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |info| format!("{:x}", info.pid)),
+            level,
+            path: PathBuf::from(entry),
+            info,
+        });
+    }
+    genies
+}
+
+// the tcp entries along path, with their levels
+fn tcp_entries(path: &str) -> Vec<(usize, String)> {
+    crate::split_path(path)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, entry)| crate::tcp::is_tcp(entry))
+        .collect()
+}
+
+pub fn run(path: &str, matches: &ArgMatches) -> bool {
+    let port = match matches.value_of("port").map(str::parse) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            warn(format!(
+                "invalid port {}",
+                matches.value_of("port").unwrap()
+            ));
+            return false;
+        }
+    };
+    let interval = match matches.value_of("interval") {
+        None => Duration::from_secs(2),
+        Some(seconds) => match seconds
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        {
+            Some(seconds) => Duration::from_secs_f64(seconds),
+            None => {
+                warn(format!("invalid interval {}", seconds));
+                return false;
+            }
+        },
+    };
+
+    // only ever on loopback: genie output is no business of the network's
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn(format!("unable to listen on port {}: {}", port, err));
+            return false;
+        }
+    };
+    if let Ok(addr) = listener.local_addr() {
+        println!("listening on http://{}", addr);
+    }
+
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                warn(format!("error accepting client: {}", err));
+                continue;
+            }
+        };
+
+        let path = path.to_string();
+        std::thread::spawn(move || {
+            if let Err(err) = serve(client, &path, interval) {
+                warn(format!("error serving client: {}", err));
+            }
+        });
+    }
+
+    true
+}
+
+// Reads the request head, returning the method, target and Host header.
+fn read_head(client: &TcpStream) -> io::Result<(String, String, Option<String>)> {
+    let mut reader = io::BufReader::new(client.take(8192));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let method = words.next().unwrap_or_default().to_string();
+    let target = words.next().unwrap_or_default().to_string();
+
+    let mut host = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("host") {
+                host = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok((method, target, host))
+}
+
+// a page elsewhere could point a hostname of its own at 127.0.0.1, so insist
+// on being addressed as localhost
+fn local_host(host: &Option<String>) -> bool {
+    let host = match host {
+        Some(host) => host,
+        None => return false,
+    };
+    let name = match host.rfind(':') {
+        Some(colon) if !host.ends_with(']') => &host[..colon],
+        _ => host,
+    };
+    matches!(name, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+fn respond(
+    client: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    client.write_all(body)
+}
+
+fn respond_json<T: Serialize>(client: &mut TcpStream, value: &T) -> io::Result<()> {
+    let body = serde_json::to_vec_pretty(value).map_err(io::Error::other)?;
+    respond(client, "200 OK", "application/json", &body)
+}
+
+fn not_found(client: &mut TcpStream) -> io::Result<()> {
+    respond(client, "404 Not Found", "text/plain", b"not found\n")
+}
+
+fn serve(mut client: TcpStream, path: &str, interval: Duration) -> io::Result<()> {
+    let (method, target, host) = read_head(&client)?;
+    if !local_host(&host) {
+        return respond(&mut client, "403 Forbidden", "text/plain", b"forbidden\n");
+    }
+    if method != "GET" {
+        return respond(
+            &mut client,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"only GET is supported\n",
+        );
+    }
+
+    let parts = target
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    match parts.as_slice() {
+        [""] => respond(&mut client, "200 OK", "text/html", INDEX.as_bytes()),
+        ["genies"] => respond_json(&mut client, &genies(path)),
+        ["genies", genie, verb @ ("get" | "info")] => {
+            let mut split = genie.split('.');
+            let name = split.next().unwrap_or_default();
+            let num = split.next();
+            let found = crate::entries(path)
+                .into_iter()
+                .find(|entry| entry.name == name && num.is_none_or(|num| entry.pid == num));
+            let entry = match found {
+                Some(entry) => entry,
+                None => return not_found(&mut client),
+            };
+
+            if *verb == "info" {
+                match crate::info::query(&entry.path) {
+                    Ok(info) => respond_json(&mut client, &info),
+                    Err(err) => respond(
+                        &mut client,
+                        "502 Bad Gateway",
+                        "text/plain",
+                        format!("{}\n", err).as_bytes(),
+                    ),
+                }
+            } else {
+                let request = format!("get\n{}\n", cookie_for(&entry, COOKIE_PREFIX));
+                match crate::request_within(&entry.path, &request, POLL_TIMEOUT) {
+                    Ok(output) => respond_json(
+                        &mut client,
+                        &Output {
+                            name: &entry.name,
+                            pid: &entry.pid,
+                            output: String::from_utf8_lossy(&output).into_owned(),
+                        },
+                    ),
+                    Err(err) => respond(
+                        &mut client,
+                        "502 Bad Gateway",
+                        "text/plain",
+                        format!("{}\n", err).as_bytes(),
+                    ),
+                }
+            }
+        }
+        ["events"] => events(client, path, interval),
+        _ => not_found(&mut client),
+    }
+}
+
+fn send_event<T: Serialize>(client: &mut TcpStream, event: &str, value: &T) -> io::Result<()> {
+    let data = serde_json::to_string(value).map_err(io::Error::other)?;
+    write!(client, "event: {}\ndata: {}\n\n", event, data)?;
+    client.flush()
+}
+
+// Server-sent events: a "genies" event with the full list whenever genies
+// come or go, and an "output" event whenever one of them has something new.
+// Once the client goes, the genies are told to forget the stream's cookie.
+fn events(client: TcpStream, path: &str, interval: Duration) -> io::Result<()> {
+    let cookie = format!("{}{}", COOKIE_PREFIX, crate::auth::random()?);
+    let result = stream_events(client, path, interval, &cookie);
+
+    let forget = format!("forget\n{}\n", cookie);
+    for entry in crate::entries(path) {
+        if !supervised(&entry) {
+            let _ = crate::request_within(&entry.path, &forget, crate::QUERY_TIMEOUT);
+        }
+    }
+    for (_, entry) in tcp_entries(path) {
+        let _ = crate::tcp::request_within(&entry, &forget, crate::QUERY_TIMEOUT);
+    }
+    result
+}
+
+fn stream_events(
+    mut client: TcpStream,
+    path: &str,
+    interval: Duration,
+    cookie: &str,
+) -> io::Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"
+    )?;
+
+    let mut known = Vec::new();
+    loop {
+        let entries = crate::entries(path);
+        let ids = entries
+            .iter()
+            .map(|entry| format!("{}.{}", entry.name, entry.pid))
+            .collect::<Vec<_>>();
+        if ids != known {
+            send_event(&mut client, "genies", &genies(path))?;
+            known = ids;
+        }
+
+        // genied passes on what the genies it runs have to say
+        let mut answers = Vec::new();
+        for entry in entries.iter().filter(|entry| !supervised(entry)) {
+            let request = format!("{}\n{}\n", verb(&entry.name), cookie);
+            if let Ok(response) = crate::request_within(&entry.path, &request, POLL_TIMEOUT) {
+                answers.push((entry.name.clone(), entry.pid.clone(), response));
+            }
+        }
+        for (_, entry) in tcp_entries(path) {
+            answers.extend(poll_tcp(&entry, cookie));
+        }
+
+        for (name, pid, response) in answers {
+            for (name, pid, output) in responses(name, pid, response).unwrap_or_default() {
+                if !output.is_empty() {
+                    let output = Output {
+                        name: &name,
+                        pid: &pid,
+                        output: String::from_utf8_lossy(&output).into_owned(),
+                    };
+                    send_event(&mut client, "output", &output)?;
+                }
+            }
+        }
+
+        std::thread::sleep(interval);
+    }
+}
@@ -0,0 +1,148 @@
+use clap::ArgMatches;
+
+use super::warn;
+
+// set by the snippets to the shell and its pid, so genie doctor can tell
+// that the shell it was run from polls, rather than one it inherited from
+pub const HOOK_VAR: &str = "GENIE_HOOK";
+
+// In each snippet @COOKIE@ is a new cookie for the shell, @GENIE@ this
+// program and @POLL@ how to poll, in the foreground or not.
+
+const BASH: &str = r#"# genie integration for bash; add `eval "$(genie init bash)"` to ~/.bashrc
+export GENIE_COOKIE=@COOKIE@
+export GENIE_HOOK=bash.$$
+
+__genie_poll() {
+    local ret=$?
+    [ -n "$GENIE_PATH" ] && @POLL@
+    return $ret
+}
+
+__genie_forget() {
+    [ -n "$GENIE_PATH" ] && @GENIE@ forget >/dev/null 2>&1
+}
+
+case ";${PROMPT_COMMAND:-};" in
+    *";__genie_poll;"*) ;;
+    *) PROMPT_COMMAND="${PROMPT_COMMAND:+$PROMPT_COMMAND;}__genie_poll" ;;
+esac
+
+# bash keeps a single EXIT trap; one already set is left alone, and can call
+# __genie_forget itself
+if [ -z "$(trap -p EXIT)" ]; then
+    trap __genie_forget EXIT
+fi
+"#;
+
+const ZSH: &str = r#"# genie integration for zsh; add `eval "$(genie init zsh)"` to ~/.zshrc
+export GENIE_COOKIE=@COOKIE@
+export GENIE_HOOK=zsh.$$
+
+__genie_poll() {
+    local ret=$?
+    [[ -n $GENIE_PATH ]] && @POLL@
+    return $ret
+}
+
+__genie_forget() {
+    [[ -n $GENIE_PATH ]] && @GENIE@ forget >/dev/null 2>&1
+}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd __genie_poll
+add-zsh-hook zshexit __genie_forget
+"#;
+
+const FISH: &str = r#"# genie integration for fish; add `genie init fish | source` to config.fish
+set -gx GENIE_COOKIE @COOKIE@
+set -gx GENIE_HOOK fish.$fish_pid
+
+function __genie_poll --on-event fish_prompt
+    if set -q GENIE_PATH
+        @POLL@
+    end
+end
+
+function __genie_forget --on-event fish_exit
+    if set -q GENIE_PATH
+        @GENIE@ forget >/dev/null 2>&1
+    end
+end
+"#;
+
+// Prints what a shell needs to evaluate at startup to poll genies before
+// each prompt with its terminal's cookie, or a random one, and have them
+// forget it on exit.
+pub fn run(matches: &ArgMatches) -> bool {
+    let shell = matches.value_of("shell").unwrap();
+    let in_background = matches.is_present("async");
+
+    let cookie = match new_cookie(matches.is_present("random-cookie")) {
+        Ok(cookie) => cookie,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+
+    let genie = this_genie();
+    let (template, genie) = match shell {
+        "fish" => (FISH, quote_fish(&genie)),
+        "zsh" => (ZSH, quote_sh(&genie)),
+        _ => (BASH, quote_sh(&genie)),
+    };
+    let poll = match (shell, in_background) {
+        (_, false) => format!("{} poll", genie),
+        ("fish", true) => format!("{} poll &; disown", genie),
+        ("zsh", true) => format!("{} poll &!", genie),
+        (_, true) => format!("({} poll &)", genie),
+    };
+
+    print!(
+        "{}",
+        template
+            .replace("@POLL@", &poll)
+            .replace("@GENIE@", &genie)
+            .replace("@COOKIE@", &cookie)
+    );
+    true
+}
+
+// Prints a cookie for the terminal we're in, for shells set up by hand.
+pub fn cookie(matches: &ArgMatches) -> bool {
+    match new_cookie(matches.is_present("random")) {
+        Ok(cookie) => {
+            println!("{}", cookie);
+            true
+        }
+        Err(err) => {
+            warn(err);
+            false
+        }
+    }
+}
+
+fn new_cookie(random: bool) -> Result<String, String> {
+    let cookie = if random {
+        crate::auth::random()
+    } else {
+        crate::cookie::generate()
+    };
+    cookie.map_err(|err| format!("unable to make up a cookie: {}", err))
+}
+
+// by full path, so the shell finds the same genie we are
+pub fn this_genie() -> String {
+    std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "genie".to_string())
+}
+
+pub fn quote_sh(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+pub fn quote_fish(word: &str) -> String {
+    format!("'{}'", word.replace('\\', r"\\").replace('\'', r"\'"))
+}
@@ -0,0 +1,133 @@
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+use super::{select, warn, Config};
+use crate::Entry;
+
+// Stops each selected genie.
+pub fn run(config: &Config, matches: &ArgMatches) -> bool {
+    let timeout = match timeout(matches) {
+        Ok(timeout) => timeout,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+
+    let (entries, mut ok) = select(config, matches);
+    for entry in entries {
+        match stop(&entry, timeout) {
+            Ok(()) => println!("stopped {}.{}", entry.name, entry.pid),
+            Err(err) => {
+                warn(err);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+pub fn timeout(matches: &ArgMatches) -> Result<Duration, String> {
+    match matches.value_of("timeout") {
+        None => Ok(Duration::from_secs(5)),
+        Some(seconds) => seconds
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| *seconds >= 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or_else(|| format!("invalid timeout {}", seconds)),
+    }
+}
+
+// Asks the genie to exit and waits for its entry to go. One that doesn't
+// answer, or doesn't go, within timeout gets SIGTERM and then SIGKILL, after
+// which we clean up after it.
+pub fn stop(entry: &Entry, timeout: Duration) -> Result<(), String> {
+    let spec = format!("{}.{}", entry.name, entry.pid);
+
+    match crate::auth::exit_within(&entry.path, timeout) {
+        Ok(()) => {
+            if gone(&entry.path, timeout) {
+                return Ok(());
+            }
+            warn(format!("{} is still there after {:?}", spec, timeout));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        // nobody is listening, so its pid may well be someone else's by now
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            return Err(format!(
+                "{} is not running (genie gc removes what it left)",
+                spec
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            return Err(format!("{}: {}", spec, err))
+        }
+        Err(err) if timed_out(&err) => {
+            warn(format!("{} did not answer within {:?}", spec, timeout))
+        }
+        Err(err) => warn(format!("{} did not answer: {}", spec, err)),
+    }
+
+    let pid = u32::from_str_radix(&entry.pid, 16)
+        .map_err(|_| format!("{} has no pid to signal", spec))? as libc::pid_t;
+
+    if !signal(pid, libc::SIGTERM).map_err(|err| format!("unable to signal {}: {}", spec, err))? {
+        return Ok(());
+    }
+    warn(format!("sent SIGTERM to {}", spec));
+    if gone(&entry.path, timeout) {
+        return Ok(());
+    }
+
+    signal(pid, libc::SIGKILL).map_err(|err| format!("unable to signal {}: {}", spec, err))?;
+    warn(format!("sent SIGKILL to {}", spec));
+
+    // it had no chance to clean up after itself
+    for path in [entry.path.clone(), crate::auth::token_path(&entry.path)] {
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn(format!("unable to remove {}: {}", path.display(), err));
+            }
+        }
+    }
+    Ok(())
+}
+
+// how a read past its timeout fails
+pub fn timed_out(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// false if there was no such process to signal
+fn signal(pid: libc::pid_t, signal: libc::c_int) -> io::Result<bool> {
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ESRCH) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+// waits up to timeout for the entry at path to go away
+fn gone(path: &Path, timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if std::fs::symlink_metadata(path).is_err() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,158 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use super::Config;
+use crate::info::Info;
+use crate::Entry;
+
+// what we know about one genie along the path
+#[derive(Serialize)]
+struct Row {
+    level: usize,
+    name: String,
+    label: Option<String>,
+    pid: Option<u32>,
+    path: String,
+    // up, stuck (not answering), refused (not letting us in) or dead
+    state: &'static str,
+    uptime: Option<u64>, // seconds
+    // None without a cookie, or for a genie that can't say
+    unseen: Option<bool>,
+}
+
+impl Row {
+    fn new(config: &Config, entry: &Entry, now: u64) -> Row {
+        let info = crate::info::query(&entry.path);
+        let mut row = Row::describe(config.cookie_for(entry), info, now, |cookie| {
+            crate::peek(&entry.path, cookie)
+        });
+        row.level = entry.level;
+        row.name = entry.name.clone();
+        row.pid = u32::from_str_radix(&entry.pid, 16).ok();
+        row.path = entry.path.display().to_string();
+        row
+    }
+
+    // A genie listening on tcp, at entry, is known by what it says of
+    // itself; its pid is on another host, so its address stands in.
+    fn tcp(config: &Config, level: usize, entry: &str, now: u64) -> Row {
+        let info = crate::tcp::request_within(entry, "info\n", crate::QUERY_TIMEOUT)
+            .and_then(|response| crate::info::Info::parse(&response));
+        let cookie = config.cookie().map(str::to_string);
+        let mut row = Row::describe(cookie, info, now, |cookie| crate::tcp::peek(entry, cookie));
+        row.level = level;
+        row.path = entry.to_string();
+        row
+    }
+
+    // what a genie's answers, or lack of them, tell us; cookie is the one it
+    // knows us by
+    fn describe<F>(cookie: Result<String, String>, info: io::Result<Info>, now: u64, peek: F) -> Row
+    where
+        F: FnOnce(&str) -> io::Result<Option<bool>>,
+    {
+        let (info, state) = match info {
+            Ok(info) => (Some(info), "up"),
+            Err(err) if super::kill::timed_out(&err) => (None, "stuck"),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => (None, "refused"),
+            Err(_) => (None, "dead"),
+        };
+        let unseen = match cookie {
+            Ok(cookie) if info.is_some() => peek(&cookie).unwrap_or(None),
+            _ => None,
+        };
+
+        Row {
+            level: 0,
+            name: info
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |info| info.name.clone()),
+            label: info.as_ref().and_then(|info| info.label.clone()),
+            pid: None,
+            path: String::new(),
+            state,
+            uptime: info.map(|info| now.saturating_sub(info.started)),
+            unseen,
+        }
+    }
+}
+
+const HEADINGS: [&str; 8] = [
+    "LEVEL", "NAME", "LABEL", "PID", "STATE", "UPTIME", "UNSEEN", "PATH",
+];
+
+// e.g. 3d4h, 2h5m, 4m10s, 12s
+fn duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{}s", minutes, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// Lists every genie along the path, as a table or as JSON.
+pub fn run(config: &Config, matches: &ArgMatches) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let mut rows = crate::entries(&config.path)
+        .iter()
+        .map(|entry| Row::new(config, entry, now))
+        .collect::<Vec<_>>();
+    for (level, entry) in crate::split_path(&config.path).iter().enumerate() {
+        if crate::tcp::is_tcp(entry) {
+            rows.push(Row::tcp(config, level, entry, now));
+        }
+    }
+    // stable, so each level keeps its order
+    rows.sort_by_key(|row| row.level);
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+        return true;
+    }
+
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut table = vec![HEADINGS.iter().map(ToString::to_string).collect::<Vec<_>>()];
+    for row in rows {
+        table.push(vec![
+            row.level.to_string(),
+            row.name,
+            or_dash(row.label),
+            or_dash(row.pid.map(|pid| pid.to_string())),
+            row.state.to_string(),
+            or_dash(row.uptime.map(duration)),
+            or_dash(
+                row.unseen
+                    .map(|unseen| (if unseen { "yes" } else { "no" }).to_string()),
+            ),
+            row.path,
+        ]);
+    }
+
+    let mut widths = vec![0; table[0].len()];
+    for line in &table {
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for line in &table {
+        let cells = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>();
+        println!("{}", cells.join("  ").trim_end());
+    }
+
+    true
+}
@@ -0,0 +1,479 @@
+use std::ffi::OsString;
+use std::fmt::Display;
+
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+
+use crate::Entry;
+
+mod completions;
+mod doctor;
+mod forget;
+mod gc;
+mod get;
+mod http;
+mod init;
+mod kill;
+mod ls;
+mod poll;
+mod project;
+mod promote;
+mod restart;
+mod send;
+
+// What every subcommand needs from the environment.
+pub struct Config {
+    pub path: String,
+    cookie: Option<String>,
+    pub colors: Colors,
+}
+
+impl Config {
+    fn new(matches: &ArgMatches) -> Result<Config, String> {
+        let path = std::env::var("GENIE_PATH").map_err(|_| "GENIE_PATH is not set")?;
+        let cookie = std::env::var(crate::cookie::COOKIE_VAR)
+            .ok()
+            .or_else(crate::cookie::for_terminal);
+        let colors = Colors::new(matches.value_of("color").unwrap_or("auto"));
+
+        Ok(Config {
+            path,
+            cookie,
+            colors,
+        })
+    }
+
+    // the cookie genies know us by, for the subcommands that need one: the
+    // one we were given, or else our terminal's
+    pub fn cookie(&self) -> Result<&str, String> {
+        self.cookie.as_deref().ok_or_else(|| {
+            format!(
+                "{} is not set, and we have no terminal to make one up for",
+                crate::cookie::COOKIE_VAR
+            )
+        })
+    }
+
+    // the cookie the genie at entry knows us by
+    pub fn cookie_for(&self, entry: &Entry) -> Result<String, String> {
+        Ok(cookie_for(entry, self.cookie()?))
+    }
+}
+
+// TODO: terminfo properly
+pub struct Colors {
+    pub magenta: &'static str,
+    pub cyan: &'static str,
+    pub white: &'static str,
+}
+
+impl Colors {
+    fn new(when: &str) -> Colors {
+        let on = match when {
+            "always" => true,
+            "never" => false,
+            _ => std::env::var_os("NO_COLOR").is_none() && unsafe { libc::isatty(1) } == 1,
+        };
+
+        if on {
+            Colors {
+                magenta: "\x1b[35m",
+                cyan: "\x1b[36m",
+                white: "\x1b[37m",
+            }
+        } else {
+            Colors {
+                magenta: "",
+                cyan: "",
+                white: "",
+            }
+        }
+    }
+}
+
+// Reports a problem, the same way for every subcommand.
+pub fn warn(what: impl Display) {
+    eprintln!("genie: {}", what);
+}
+
+// Finds the genie named by spec, "name" or "name.pid" with pid in hex as in
+// its socket name, or else labelled spec; the first along the path if there
+// are several.
+pub fn resolve(config: &Config, spec: &str) -> Result<Entry, String> {
+    let mut split = spec.splitn(2, '.');
+    let name = split.next().unwrap_or_default();
+    let pid = split.next();
+
+    let mut entries = crate::entries(&config.path);
+    if let Some(i) = entries
+        .iter()
+        .position(|entry| entry.name == name && pid.is_none_or(|pid| entry.pid == pid))
+    {
+        return Ok(entries.swap_remove(i));
+    }
+
+    entries
+        .into_iter()
+        .find(|entry| {
+            crate::info::query(&entry.path).is_ok_and(|info| info.label.as_deref() == Some(spec))
+        })
+        .ok_or_else(|| format!("no genie {} along GENIE_PATH", spec))
+}
+
+// Finds the genies picked out on the command line: with --all every one
+// along the path, and otherwise those whose name, name.pid or label matches
+// one of the given patterns, which may use * and ?. A pattern that matches
+// nothing is reported, and makes the second value false.
+pub fn select(config: &Config, matches: &ArgMatches) -> (Vec<Entry>, bool) {
+    let entries = crate::entries(&config.path);
+    if matches.is_present("all") {
+        return (entries, true);
+    }
+
+    // asked for only when a pattern doesn't match by name
+    let mut labels = vec![None; entries.len()];
+    let mut label = |i: usize, entry: &Entry| -> Option<String> {
+        labels[i]
+            .get_or_insert_with(|| {
+                crate::info::query(&entry.path)
+                    .ok()
+                    .and_then(|info| info.label)
+            })
+            .clone()
+    };
+
+    let mut picked = vec![false; entries.len()];
+    let mut ok = true;
+    for pattern in matches.values_of("genie").unwrap_or_default() {
+        let re = glob(pattern);
+        let mut found = false;
+        for (i, entry) in entries.iter().enumerate() {
+            if re.is_match(&entry.name)
+                || re.is_match(&format!("{}.{}", entry.name, entry.pid))
+                || label(i, entry).is_some_and(|label| re.is_match(&label))
+            {
+                picked[i] = true;
+                found = true;
+            }
+        }
+        if !found {
+            warn(format!("no genie {} along GENIE_PATH", pattern));
+            ok = false;
+        }
+    }
+
+    let selected = entries
+        .into_iter()
+        .zip(picked)
+        .filter_map(|(entry, picked)| if picked { Some(entry) } else { None })
+        .collect();
+    (selected, ok)
+}
+
+// a shell-style pattern as an anchored regex
+fn glob(pattern: &str) -> regex::Regex {
+    let re = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    regex::Regex::new(&format!("^{}$", re)).unwrap()
+}
+
+// What the genie at entry knows the holder of cookie by: one run by genied is
+// polled through it, and so by the cookie genied passes on.
+pub fn cookie_for(entry: &Entry, cookie: &str) -> String {
+    if supervised(entry) {
+        crate::cookie::of_user(crate::perms::uid(), cookie)
+    } else {
+        cookie.to_string()
+    }
+}
+
+// whether the genie was started by genied, which starts it again if it goes
+pub fn supervised(entry: &Entry) -> bool {
+    entry
+        .path
+        .parent()
+        .and_then(std::path::Path::file_name)
+        .and_then(|dir| dir.to_str())
+        .is_some_and(crate::is_supervised_dir)
+}
+
+fn app() -> App<'static, 'static> {
+    let relocate = |command: App<'static, 'static>| {
+        command
+            .arg(Arg::from_usage(
+                "-n, --dry-run 'say where genies would go without moving them'",
+            ))
+            .arg(Arg::from_usage("<genie>... 'name, name.pid or label'"))
+    };
+
+    let select = |name, about| {
+        SubCommand::with_name(name)
+            .about(about)
+            .arg(Arg::from_usage(
+                "[genie]... 'name, name.pid or label, possibly with * and ?'",
+            ))
+            .arg(Arg::from_usage("-a, --all 'every genie along GENIE_PATH'"))
+            .arg(
+                Arg::with_name("timeout")
+                    .short("t")
+                    .long("timeout")
+                    .takes_value(true)
+                    .value_name("seconds")
+                    .help(
+                        "how long to wait for each genie to go before signalling it [default: 5]",
+                    ),
+            )
+            .group(
+                ArgGroup::with_name("which")
+                    .args(&["genie", "all"])
+                    .required(true),
+            )
+    };
+
+    let project = |name, about| {
+        SubCommand::with_name(name)
+            .about(about)
+            .arg(
+                Arg::with_name("file")
+                    .short("f")
+                    .long("file")
+                    .takes_value(true)
+                    .value_name("Geniefile"),
+            )
+            .arg(Arg::from_usage(
+                "[label]... 'only these genies from the manifest'",
+            ))
+    };
+
+    App::new("genie")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .author("David L. L. Thomas <davidleothomas@gmail.com>")
+        .about("talk to and manage genies")
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .takes_value(true)
+                .value_name("when")
+                .possible_values(&["auto", "always", "never"])
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("poll").about("show what's new from every genie"))
+        .subcommand(
+            SubCommand::with_name("forget")
+                .about("tell every genie we won't be polling with our cookie again"),
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("print shell code that polls genies before each prompt")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                )
+                .arg(Arg::from_usage(
+                    "--async 'poll in the background rather than hold up the prompt'",
+                ))
+                .arg(Arg::from_usage(
+                    "--random-cookie 'a new cookie for each shell rather than one per terminal'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("cookie")
+                .about("print a cookie for this terminal or tmux pane")
+                .arg(Arg::from_usage(
+                    "--random 'a random one, the same for no other terminal'",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("print shell code that completes genie commands and the genies running")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&["bash", "zsh", "fish"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("complete")
+                .about("list the genies that could complete a word, for completion scripts")
+                .setting(AppSettings::Hidden)
+                .arg(Arg::from_usage("[prefix] 'what has been typed so far'")),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor").about(
+                "check what notifications depend on, probing every genie, and suggest fixes",
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("show a genie's output, as of when we last polled it")
+                .arg(Arg::from_usage("<genie> 'name, name.pid or label'"))
+                .arg(Arg::from_usage(
+                    "-l, --latest 'its latest output, whether or not we have seen it'",
+                ))
+                .arg(Arg::from_usage(
+                    "--json 'print a JSON object with the output and where it came from'",
+                ))
+                .after_help(
+                    "Exits with 0 if the genie had output, 2 if it had none and 1 on error.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("list genies along GENIE_PATH")
+                .arg(Arg::from_usage(
+                    "--json 'print a JSON array instead of a table'",
+                )),
+        )
+        .subcommand(relocate(
+            SubCommand::with_name("promote")
+                .about("move genies to the next directory along GENIE_PATH"),
+        ))
+        .subcommand(relocate(
+            SubCommand::with_name("demote")
+                .about("move genies to the previous directory along GENIE_PATH"),
+        ))
+        .subcommand(relocate(
+            SubCommand::with_name("move")
+                .about("move genies to the directory at a level along GENIE_PATH")
+                .arg(Arg::from_usage(
+                    "<level> 'a level counted from 0, first or last'",
+                )),
+        ))
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("send a genie a request and show its response as is")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::from_usage(
+                    "-v, --verbose 'trace the bytes exchanged on stderr'",
+                ))
+                .arg(Arg::from_usage(
+                    "--raw 'send protocol text as is, with escapes like \\n, or stdin'",
+                ))
+                .arg(Arg::from_usage(
+                    "<genie> 'name, name.pid, label or tcp://host:port'",
+                ))
+                .arg(Arg::from_usage(
+                    "[request]... 'a verb and its arguments, sent one to a line'",
+                )),
+        )
+        .subcommand(select("kill", "stop genies"))
+        .subcommand(select(
+            "restart",
+            "stop genies and start them again the way they were started",
+        ))
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("remove what dead genies left behind")
+                .arg(Arg::from_usage("--logs 'remove their logs too'")),
+        )
+        .subcommand(project(
+            "up",
+            "start the project's genies that aren't already running",
+        ))
+        .subcommand(project("down", "stop the project's running genies"))
+        .subcommand(project(
+            "status",
+            "show which of the project's genies are running",
+        ))
+        .subcommand(
+            SubCommand::with_name("http")
+                .about("serve genies and their output as JSON over localhost http")
+                .arg(
+                    Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .takes_value(true)
+                        .value_name("port"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .short("n")
+                        .long("interval")
+                        .takes_value(true)
+                        .value_name("seconds"),
+                ),
+        )
+}
+
+// Runs the genie command line in args, returning the exit status.
+pub fn main<I, T>(args: I) -> i32
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = app().get_matches_from(args);
+    let (command, sub) = matches.subcommand();
+    let sub = sub.unwrap();
+
+    // run from a shell's startup file, maybe before GENIE_PATH is set
+    let ok = match command {
+        "init" => Some(init::run(sub)),
+        "cookie" => Some(init::cookie(sub)),
+        "doctor" => Some(doctor::run()),
+        "completions" => Some(completions::run(sub)),
+        "complete" => Some(completions::complete(sub)),
+        _ => None,
+    };
+    if let Some(ok) = ok {
+        return if ok { 0 } else { 1 };
+    }
+
+    let config = match Config::new(sub) {
+        Ok(config) => config,
+        Err(err) => {
+            warn(err);
+            return 1;
+        }
+    };
+
+    let ok = match command {
+        "poll" => poll::run(&config),
+        "forget" => forget::run(&config),
+        "get" => return get::run(&config, sub),
+        "ls" => ls::run(&config, sub),
+        "promote" | "demote" | "move" => promote::run(&config, command, sub),
+        "kill" => kill::run(&config, sub),
+        "restart" => restart::run(&config, sub),
+        "send" => send::run(&config, sub),
+        "gc" => gc::run(&config, sub),
+        "up" | "down" | "status" => project::run(command, &config.path, sub),
+        "http" => http::run(&config.path, sub),
+        _ => unreachable!(),
+    };
+
+    if ok {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob;
+
+    #[test]
+    fn globs_match_whole_names() {
+        let re = glob("watch*");
+        assert!(re.is_match("watch"));
+        assert!(re.is_match("watch.2a"));
+        assert!(!re.is_match("stopwatch"));
+
+        let re = glob("w?tch.2a");
+        assert!(re.is_match("watch.2a"));
+        assert!(!re.is_match("wtch.2a"));
+        assert!(!re.is_match("watch.2ab"));
+    }
+
+    #[test]
+    fn globs_take_everything_else_literally() {
+        let re = glob("a.b+(c)[d]");
+        assert!(re.is_match("a.b+(c)[d]"));
+        assert!(!re.is_match("axbb(c)d"));
+        assert!(glob("").is_match(""));
+        assert!(!glob("").is_match("watch"));
+        assert!(glob("*").is_match(""));
+    }
+}
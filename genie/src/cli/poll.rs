@@ -0,0 +1,162 @@
+use std::io::{self, prelude::*};
+use std::time::Duration;
+
+use super::kill::timed_out;
+use super::{warn, Colors, Config};
+
+// genied answers for all of its genies at once
+pub fn verb(name: &str) -> &'static str {
+    if name == crate::SUPERVISOR_NAME {
+        "multipoll"
+    } else {
+        "poll"
+    }
+}
+
+// the name, pid and response of each genie a response to verb() is from
+pub fn responses(
+    name: String,
+    pid: String,
+    response: Vec<u8>,
+) -> io::Result<Vec<(String, String, Vec<u8>)>> {
+    if name == crate::SUPERVISOR_NAME {
+        crate::split_multi(&response)
+    } else {
+        Ok(vec![(name, pid, response)])
+    }
+}
+
+fn print(colors: &Colors, name: String, pid: String, response: Vec<u8>, header_printed: &mut bool) {
+    let responses = match responses(name.clone(), pid, response) {
+        Ok(responses) => responses,
+        Err(err) => {
+            warn(format!("{}: {}", name, err));
+            return;
+        }
+    };
+
+    for (name, pid, response) in responses {
+        if !response.is_empty() {
+            if !*header_printed {
+                *header_printed = true;
+                println!("\n{}~~~{}\n", colors.cyan, colors.white);
+            }
+
+            for line in String::from_utf8_lossy(&response).lines() {
+                println!(
+                    "{}{}{}({}): {}",
+                    colors.magenta, name, colors.white, pid, line
+                );
+            }
+        }
+    }
+}
+
+// how long a genie gets to answer a poll over tcp, or from genie http, so
+// one that's unreachable or hung can't hold up whoever is waiting; a genied
+// may itself wait QUERY_TIMEOUT on the genies it runs
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// a genie listening on tcp says who it is when asked
+pub fn poll_tcp(entry: &str, cookie: &str) -> io::Result<(String, String, Vec<u8>)> {
+    let info = crate::tcp::request_within(entry, "info\n", crate::QUERY_TIMEOUT)?;
+    let info = crate::info::Info::parse(&info)?;
+    let request = format!("{}\n{}\n", verb(&info.name), cookie);
+    let response = crate::tcp::request_within(entry, &request, POLL_TIMEOUT)?;
+    Ok((info.name, format!("{:x}", info.pid), response))
+}
+
+// Prints whatever each genie along the path has that we haven't seen, under
+// a header if there is anything at all. Genies run by genied are asked
+// through genied rather than one by one.
+pub fn run(config: &Config) -> bool {
+    let cookie = match config.cookie() {
+        Ok(cookie) => cookie,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+
+    let re = regex::Regex::new(crate::SOCKNAME_PATTERN).unwrap();
+
+    let mut header_printed = false;
+
+    for dir in crate::split_path(&config.path) {
+        if crate::tcp::is_tcp(&dir) {
+            match poll_tcp(&dir, cookie) {
+                Ok((name, pid, response)) => {
+                    print(&config.colors, name, pid, response, &mut header_printed)
+                }
+                Err(err) if timed_out(&err) => warn(format!("{}: not answering", dir)),
+                Err(err) => warn(format!("{}: {}", dir, err)),
+            }
+            continue;
+        }
+
+        let dir = match std::fs::read_dir(dir) {
+            Err(_) => continue,
+            Ok(dir) => dir,
+        };
+
+        for entry in dir {
+            let entry = match entry {
+                Err(err) => {
+                    warn(format!("error retrieving directory entry: {}", err));
+                    continue;
+                }
+
+                Ok(entry) => entry,
+            };
+
+            let filename = entry.file_name();
+            let captures = match filename.to_str().and_then(|name| re.captures(name)) {
+                None => continue,
+                Some(captures) => captures,
+            };
+
+            let name = captures[1].to_string();
+            let pid = captures[2].to_string();
+
+            let path = entry.path();
+            let mut stream = match crate::connect(&path) {
+                Err(err) => {
+                    match err.kind() {
+                        io::ErrorKind::ConnectionRefused => {
+                            let _ = std::fs::remove_file(&path);
+                        }
+
+                        _ => warn(format!(
+                            "error connecting to socket at {}: {}",
+                            path.display(),
+                            err
+                        )),
+                    }
+                    continue;
+                }
+
+                Ok(stream) => stream,
+            };
+
+            let request = format!("{}\n{}\n", verb(&name), cookie);
+            if let Err(err) = stream.write_all(request.as_bytes()) {
+                warn(format!("{}: error writing to socket: {}", name, err));
+                continue;
+            }
+
+            let mut response = Vec::new();
+            if let Err(err) = stream.read_to_end(&mut response) {
+                warn(format!("{}: error reading from socket: {}", name, err));
+                continue;
+            }
+
+            print(&config.colors, name, pid, response, &mut header_printed);
+        }
+    }
+
+    if header_printed {
+        println!()
+    }
+
+    true
+}
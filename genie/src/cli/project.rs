@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use clap::ArgMatches;
+
+use super::warn;
+use crate::info::Info;
+use crate::manifest::{self, Manifest, Spec};
+
+// Runs up, down or status for the project named by the matches.
+pub fn run(command: &str, path: &str, matches: &ArgMatches) -> bool {
+    let project = match Project::open(matches.value_of("file")) {
+        Ok(project) => project,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+    let labels = matches
+        .values_of("label")
+        .map(|labels| labels.map(String::from).collect::<Vec<_>>());
+
+    match command {
+        "up" => project.up(path, &labels),
+        "down" => project.down(path, &labels),
+        _ => project.status(path, &labels),
+    }
+}
+
+// how long down waits for a genie's socket to go away
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The genies described by a Geniefile, run from the directory holding it.
+pub struct Project {
+    dir: PathBuf,
+    manifest: Manifest,
+}
+
+impl Project {
+    pub fn open(file: Option<&str>) -> io::Result<Project> {
+        let file = match file {
+            Some(file) => PathBuf::from(file),
+            None => manifest::locate(&std::env::current_dir()?).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "no {} here or in any parent directory",
+                        manifest::MANIFEST_NAME
+                    ),
+                )
+            })?,
+        };
+
+        let manifest = manifest::load(&file)?;
+        // genies report their cwd with symlinks resolved
+        let dir = file
+            .canonicalize()?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        Ok(Project { dir, manifest })
+    }
+
+    fn selected<'a>(
+        &'a self,
+        labels: &'a Option<Vec<String>>,
+    ) -> impl Iterator<Item = (&'a String, &'a Spec)> {
+        self.manifest
+            .genies
+            .iter()
+            .filter(move |(label, _)| match labels {
+                Some(labels) => labels.contains(label),
+                None => true,
+            })
+    }
+
+    fn check_labels(&self, labels: &Option<Vec<String>>) -> bool {
+        let mut ok = true;
+        for label in labels.iter().flatten() {
+            if !self.manifest.genies.contains_key(label) {
+                warn(format!("{}: not in the manifest", label));
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    // The project's genies that are running along path, by label.
+    fn running(&self, path: &str) -> HashMap<String, (PathBuf, Info)> {
+        let mut running = HashMap::new();
+        for entry in crate::entries(path) {
+            // older genies don't answer info, and stale sockets don't answer
+            // at all
+            let info = match crate::info::query(&entry.path) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+            if info.cwd != self.dir {
+                continue;
+            }
+            if let Some(label) = info.label.clone() {
+                running.entry(label).or_insert((entry.path, info));
+            }
+        }
+        running
+    }
+
+    pub fn up(&self, path: &str, labels: &Option<Vec<String>>) -> bool {
+        let mut ok = self.check_labels(labels);
+        let running = self.running(path);
+
+        for (label, spec) in self.selected(labels) {
+            if let Some((_, info)) = running.get(label) {
+                println!("{}: already up as {}.{:x}", label, info.name, info.pid);
+                continue;
+            }
+
+            // the genie prints its socket once bound and daemonized
+            let output = Command::new(&spec.command)
+                .arg("--singleton")
+                .arg("--label")
+                .arg(label)
+                .args(&spec.args)
+                .current_dir(&self.dir)
+                .stdin(Stdio::null())
+                .output();
+
+            match output {
+                Ok(output) if output.status.success() => println!(
+                    "{}: up at {}",
+                    label,
+                    String::from_utf8_lossy(&output.stdout).trim()
+                ),
+                Ok(output) => {
+                    warn(format!(
+                        "{}: failed to start: {}",
+                        label,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                    ok = false;
+                }
+                Err(err) => {
+                    warn(format!(
+                        "{}: unable to run {}: {}",
+                        label, spec.command, err
+                    ));
+                    ok = false;
+                }
+            }
+        }
+
+        ok
+    }
+
+    pub fn down(&self, path: &str, labels: &Option<Vec<String>>) -> bool {
+        let mut ok = self.check_labels(labels);
+        let running = self.running(path);
+
+        let mut exiting = Vec::new();
+        for (label, _) in self.selected(labels) {
+            let (socket_path, info) = match running.get(label) {
+                Some(running) => running,
+                None => continue,
+            };
+
+            match crate::auth::exit(socket_path) {
+                Ok(_) => exiting.push((label, socket_path, info)),
+                Err(err) => {
+                    warn(format!(
+                        "{}: unable to ask {}.{:x} to exit: {}",
+                        label, info.name, info.pid, err
+                    ));
+                    ok = false;
+                }
+            }
+        }
+
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        for (label, socket_path, info) in exiting {
+            while socket_path.exists() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            if socket_path.exists() {
+                warn(format!(
+                    "{}: {}.{:x} hasn't exited",
+                    label, info.name, info.pid
+                ));
+                ok = false;
+            } else {
+                println!("{}: down", label);
+            }
+        }
+
+        ok
+    }
+
+    pub fn status(&self, path: &str, labels: &Option<Vec<String>>) -> bool {
+        let ok = self.check_labels(labels);
+        let running = self.running(path);
+
+        for (label, spec) in self.selected(labels) {
+            match running.get(label) {
+                Some((_, info)) => println!(
+                    "{}\t{}\tup as {}.{:x}",
+                    label, spec.command, info.name, info.pid
+                ),
+                None => println!("{}\t{}\tdown", label, spec.command),
+            }
+        }
+
+        ok
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+
+use super::{resolve, supervised, warn, Config};
+use crate::Entry;
+
+// where along the path a genie is going
+enum Target {
+    Further,
+    Nearer,
+    Level(usize),
+    First,
+    Last,
+}
+
+// Moves each genie's entry, and the token that goes with it, along the path:
+// one directory further for promote, one nearer for demote, or to the given
+// level for move.
+pub fn run(config: &Config, command: &str, matches: &ArgMatches) -> bool {
+    let target = match (command, matches.value_of("level")) {
+        ("promote", _) => Target::Further,
+        ("demote", _) => Target::Nearer,
+        (_, Some("first")) => Target::First,
+        (_, Some("last")) => Target::Last,
+        (_, level) => match level.unwrap_or_default().parse() {
+            Ok(level) => Target::Level(level),
+            Err(_) => {
+                warn(format!("invalid level {}", level.unwrap_or_default()));
+                return false;
+            }
+        },
+    };
+    let dry_run = matches.is_present("dry-run");
+
+    let mut ok = true;
+    for spec in matches.values_of("genie").unwrap() {
+        if let Err(err) = relocate(config, spec, &target, dry_run) {
+            warn(err);
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn relocate(config: &Config, spec: &str, target: &Target, dry_run: bool) -> Result<(), String> {
+    let entry = resolve(config, spec)?;
+    if supervised(&entry) {
+        return Err(format!("{} is run by genied, which keeps it close", spec));
+    }
+    if entry.name == crate::SUPERVISOR_NAME {
+        return Err(format!(
+            "{} keeps the genies it runs beside it, so stays put; restart it elsewhere",
+            spec
+        ));
+    }
+
+    let dirs = crate::split_path(&config.path);
+    let level = match target {
+        Target::Further => entry.level + 1,
+        Target::Nearer => entry
+            .level
+            .checked_sub(1)
+            .ok_or_else(|| format!("{} is already first along GENIE_PATH", spec))?,
+        Target::Level(level) => *level,
+        Target::First => 0,
+        Target::Last => dirs
+            .iter()
+            .rposition(|dir| !crate::tcp::is_tcp(dir))
+            .unwrap_or(0),
+    };
+    let dir = match dirs.get(level) {
+        Some(dir) if !crate::tcp::is_tcp(dir) => dir,
+        Some(dir) => return Err(format!("cannot move {} to {}", spec, dir)),
+        None => match target {
+            Target::Further => return Err(format!("path exhausted, cannot promote {}", spec)),
+            _ => return Err(format!("no level {} along GENIE_PATH", level)),
+        },
+    };
+    if level == entry.level {
+        println!("{} is already at level {}", spec, level);
+        return Ok(());
+    }
+
+    let filename = entry.path.file_name().expect("unable to get file name");
+    let dst = Path::new(dir).join(filename);
+    if dry_run {
+        println!("would move {} to {}", entry.path.display(), dst.display());
+        return Ok(());
+    }
+
+    let moved =
+        move_entry(&entry, dir, &dst).map_err(|err| format!("failed to move {}: {}", spec, err))?;
+    println!("moved {} to {}", spec, moved.display());
+    Ok(())
+}
+
+// Renames the entry, its token and its log into dir: the token first, so that
+// the entry is never anywhere without it, and back if the entry can't
+// follow. Across filesystems, where that can't be done, a registry file is
+// copied, while a genie listening on a socket in the old directory is asked
+// to bind another in the new one.
+fn move_entry(entry: &Entry, dir: &str, dst: &Path) -> io::Result<PathBuf> {
+    // a genie without a readable token can only be stopped with the
+    // privileged cookie
+    let token = crate::auth::token_path(&entry.path);
+    let moved_token = match std::fs::rename(&token, crate::auth::token_path(dst)) {
+        Ok(()) => true,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => false,
+        Err(err) => return Err(err),
+    };
+
+    match std::fs::rename(&entry.path, dst) {
+        Ok(()) => {
+            move_logs(&entry.path, dst);
+            Ok(dst.to_path_buf())
+        }
+        Err(err) => {
+            if moved_token {
+                std::fs::rename(crate::auth::token_path(dst), &token)?;
+            }
+            if err.raw_os_error() != Some(libc::EXDEV) {
+                return Err(err);
+            }
+
+            if std::fs::symlink_metadata(&entry.path)?.is_file() {
+                copy_registry(&entry.path, dst)?;
+                Ok(dst.to_path_buf())
+            } else {
+                crate::auth::move_to(&entry.path, dir)
+            }
+        }
+    }
+}
+
+// Moves the log a genie keeps beside its entry, and those rotated out of it,
+// along with the entry, where the genie will go on writing, rotating and in
+// the end removing it.
+fn move_logs(src: &Path, dst: &Path) {
+    let (src, dst) = (crate::log::logname(src), crate::log::logname(dst));
+    let logs = std::iter::once((src.clone(), dst.clone())).chain(
+        (1..=crate::log::KEEP)
+            .map(|n| (crate::log::rotated(&src, n), crate::log::rotated(&dst, n))),
+    );
+    for (src, dst) in logs {
+        match std::fs::rename(&src, &dst) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn(format!("unable to move {}: {}", src.display(), err))
+            }
+            _ => (),
+        }
+    }
+}
+
+fn copy_registry(src: &Path, dst: &Path) -> io::Result<()> {
+    let meta = std::fs::symlink_metadata(src)?;
+    let contents = std::fs::read(src)?;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(meta.mode() & 0o777)
+        .open(dst)?;
+    file.write_all(&contents)?;
+    if meta.mode() & 0o060 != 0 {
+        crate::perms::share(dst, meta.gid())?;
+    }
+
+    let token = crate::auth::token_path(src);
+    if token.exists() {
+        crate::auth::copy_token(src, dst)?;
+        std::fs::remove_file(&token)?;
+    }
+    std::fs::remove_file(src)
+}
@@ -0,0 +1,133 @@
+use std::io::{self, prelude::*};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use super::{kill, select, supervised, warn, Config};
+use crate::Entry;
+
+// Stops each selected genie and starts it again with the arguments and
+// working directory it reported when it started, in the same directory
+// along the path. Those run by genied are restarted by genied.
+pub fn run(config: &Config, matches: &ArgMatches) -> bool {
+    let timeout = match kill::timeout(matches) {
+        Ok(timeout) => timeout,
+        Err(err) => {
+            warn(err);
+            return false;
+        }
+    };
+
+    let (entries, mut ok) = select(config, matches);
+    for entry in entries {
+        if let Err(err) = restart(config, &entry, timeout) {
+            warn(err);
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn restart(config: &Config, entry: &Entry, timeout: Duration) -> Result<(), String> {
+    let spec = format!("{}.{}", entry.name, entry.pid);
+    if supervised(entry) {
+        return restart_supervised(config, entry, &spec);
+    }
+
+    let info = crate::info::query(&entry.path)
+        .map_err(|err| format!("unable to ask {} how it was started: {}", spec, err))?;
+    let (program, args) = info
+        .args
+        .split_first()
+        .ok_or_else(|| format!("{} did not say how it was started", spec))?;
+
+    kill::stop(entry, timeout)?;
+
+    // a relative program path was relative to where it was started
+    let program = if program.contains('/') {
+        info.cwd.join(program)
+    } else {
+        PathBuf::from(program)
+    };
+    // in the directory it was in, to be promoted along the rest of the path
+    let dir = entry
+        .path
+        .parent()
+        .map(|dir| dir.display().to_string())
+        .ok_or_else(|| format!("{} is in no directory", spec))?;
+    let mut genie_path = vec![dir];
+    genie_path.extend(
+        crate::split_path(&config.path)
+            .into_iter()
+            .skip(entry.level + 1),
+    );
+
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .current_dir(&info.cwd)
+        .env("GENIE_PATH", genie_path.join(":"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    // of its own session, so that one staying in the foreground doesn't go
+    // with our terminal
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|err| format!("unable to start {}: {}", program.display(), err))?;
+
+    // once ready, a genie reports where it's listening, whether it then goes
+    // or, started with --foreground, stays
+    let mut socket_path = String::new();
+    let stdout = child.stdout.take().expect("stdout was piped");
+    let _ = io::BufReader::new(stdout).read_line(&mut socket_path);
+    if socket_path.trim().is_empty() {
+        let status = child
+            .wait()
+            .map_err(|err| format!("error waiting for {}: {}", spec, err))?;
+        return Err(format!("{} did not start again ({})", spec, status));
+    }
+
+    println!("restarted {} at {}", spec, socket_path.trim());
+    Ok(())
+}
+
+// genied runs the genie, from a directory named after genied's pid, and so
+// is asked to stop and start it, wherever along the path genied now is
+fn restart_supervised(config: &Config, entry: &Entry, spec: &str) -> Result<(), String> {
+    let re = regex::Regex::new(crate::SUPERVISED_DIR_PATTERN).unwrap();
+    let pid = entry
+        .path
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(|dir| dir.to_str())
+        .and_then(|dir| re.captures(dir))
+        .map(|captures| captures[1].to_string())
+        .ok_or_else(|| format!("{} has no genied to restart it", spec))?;
+    let genied = crate::entries(&config.path)
+        .into_iter()
+        .find(|genied| genied.name == crate::SUPERVISOR_NAME && genied.pid == pid)
+        .ok_or_else(|| {
+            format!(
+                "{}'s genied, {}.{}, is not along GENIE_PATH",
+                spec,
+                crate::SUPERVISOR_NAME,
+                pid
+            )
+        })?;
+    let socket_path = crate::auth::restart(&genied.path, &entry.pid)
+        .map_err(|err| format!("unable to restart {}: {}", spec, err))?;
+    println!("restarted {} at {}", spec, socket_path.display());
+    Ok(())
+}
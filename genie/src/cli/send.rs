@@ -0,0 +1,165 @@
+use std::io::{self, prelude::*};
+use std::net::Shutdown;
+use std::time::Instant;
+
+use clap::ArgMatches;
+
+use super::{resolve, warn, Config};
+
+// Sends a request to a genie, either a verb and its arguments, one to a line,
+// or with --raw protocol text as is, and prints whatever comes back.
+pub fn run(config: &Config, matches: &ArgMatches) -> bool {
+    match send(config, matches) {
+        Ok(()) => true,
+        Err(err) => {
+            warn(err);
+            false
+        }
+    }
+}
+
+fn send(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let spec = matches.value_of("genie").unwrap();
+    let words = matches.values_of("request").unwrap_or_default();
+    let verbose = matches.is_present("verbose");
+
+    let request = if !matches.is_present("raw") {
+        if words.len() == 0 {
+            return Err("no request to send".to_string());
+        }
+        words
+            .map(|word| format!("{}\n", word))
+            .collect::<String>()
+            .into_bytes()
+    } else if words.len() == 0 {
+        let mut request = Vec::new();
+        io::stdin()
+            .read_to_end(&mut request)
+            .map_err(|err| format!("error reading request: {}", err))?;
+        request
+    } else {
+        unescape(&words.collect::<Vec<_>>().join(" "))?
+    };
+
+    let failed = |err: io::Error| format!("{}: {}", spec, err);
+    let started = Instant::now();
+    let response = if crate::tcp::is_tcp(spec) {
+        let mut stream = crate::tcp::connect(spec).map_err(failed)?;
+        trace(verbose, format!("* connected to {}", spec));
+        exchange(&mut stream, &request, verbose, |stream| {
+            stream.shutdown(Shutdown::Write)
+        })
+    } else {
+        let entry = resolve(config, spec)?;
+        let mut stream = crate::connect(&entry.path).map_err(failed)?;
+        trace(verbose, format!("* connected to {}", entry.path.display()));
+        exchange(&mut stream, &request, verbose, |stream| {
+            stream.shutdown(Shutdown::Write)
+        })
+    }
+    .map_err(failed)?;
+    trace(
+        verbose,
+        format!(
+            "* closed after {} bytes in {:?}",
+            response.len(),
+            started.elapsed()
+        ),
+    );
+
+    io::stdout()
+        .write_all(&response)
+        .map_err(|err| format!("error writing output: {}", err))
+}
+
+// Writes request, tells the genie that's all, and reads until it hangs up.
+fn exchange<S, F>(stream: &mut S, request: &[u8], verbose: bool, shutdown: F) -> io::Result<Vec<u8>>
+where
+    S: Read + Write,
+    F: FnOnce(&S) -> io::Result<()>,
+{
+    stream.write_all(request)?;
+    trace(
+        verbose,
+        format!("> {} ({} bytes)", request.escape_ascii(), request.len()),
+    );
+    shutdown(stream)?;
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(response),
+            Ok(length) => {
+                let chunk = &buffer[..length];
+                trace(
+                    verbose,
+                    format!("< {} ({} bytes)", chunk.escape_ascii(), length),
+                );
+                response.extend_from_slice(chunk);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn trace(verbose: bool, line: String) {
+    if verbose {
+        eprintln!("{}", line);
+    }
+}
+
+// Turns \n, \r, \t, \0, \\ and \xHH in text into the bytes they stand for.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => bytes.push(byte),
+                    _ => return Err(format!("bad escape \\x{}", hex)),
+                }
+            }
+            Some(c) => return Err(format!("bad escape \\{}", c)),
+            None => return Err("trailing \\".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unescape;
+
+    #[test]
+    fn unescapes_what_it_knows() {
+        assert_eq!(unescape("plain text").unwrap(), b"plain text");
+        assert_eq!(
+            unescape(r"a\nb\r\tc\0\\\x41\xff").unwrap(),
+            b"a\nb\r\tc\0\\A\xff"
+        );
+        assert_eq!(unescape("é").unwrap(), "é".as_bytes());
+        assert_eq!(unescape("").unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        for text in [r"\q", "trailing\\", r"\x4", r"\xzz", r"\x", r"\xé1"] {
+            assert!(unescape(text).is_err(), "{:?}", text);
+        }
+    }
+}
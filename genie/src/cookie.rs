@@ -0,0 +1,103 @@
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::io::RawFd;
+
+// the variable a client finds its cookie in
+pub const COOKIE_VAR: &str = "GENIE_COOKIE";
+
+// A genie shows each cookie new output once, so two shells sharing a cookie
+// steal each other's notifications. This makes up one that's the same every
+// time for the terminal we're in, but differs from any other's: that of the
+// tmux pane, if we're in one (panes share an environment), or else of the
+// tty on stdin or stderr. The host goes in too, for genies listening on tcp.
+pub fn for_terminal() -> Option<String> {
+    let terminal = match (std::env::var("TMUX"), std::env::var("TMUX_PANE")) {
+        // TMUX is the server's socket path, pid and session
+        (Ok(tmux), Ok(pane)) if !pane.is_empty() => {
+            let server = tmux
+                .rsplit_once(',')
+                .map_or(tmux.as_str(), |(server, _)| server);
+            format!("tmux {} {}", server, pane)
+        }
+        _ => format!("tty {}", tty(0).or_else(|| tty(2))?),
+    };
+
+    Some(format!(
+        "{:016x}",
+        fnv1a(format!("{} {}", hostname(), terminal).as_bytes())
+    ))
+}
+
+// One for our terminal, or a random one if we don't have a terminal.
+pub fn generate() -> io::Result<String> {
+    match for_terminal() {
+        Some(cookie) => Ok(cookie),
+        None => crate::auth::random(),
+    }
+}
+
+fn tty(fd: RawFd) -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::ttyname_r(fd, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } != 0 {
+        return String::new();
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    name.to_string_lossy().into_owned()
+}
+
+// The cookie genied passes on to the genies it runs for the client with uid,
+// so that users sharing a genie keep track of what they've seen separately.
+pub fn of_user(uid: u32, cookie: &str) -> String {
+    format!("{:08x}{}", uid, cookie)
+}
+
+// 64 bit FNV-1a, plenty to keep a user's terminals apart, and the same from
+// one build to the next
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_as_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn keeps_users_apart() {
+        assert_eq!(of_user(1000, "abc"), "000003e8abc");
+        assert_ne!(of_user(0, "abc"), of_user(1, "abc"));
+    }
+
+    #[test]
+    fn follows_the_tmux_pane() {
+        std::env::set_var("TMUX", "/tmp/tmux-1000/default,4242,0");
+        std::env::set_var("TMUX_PANE", "%1");
+        let cookie = for_terminal().unwrap();
+        assert_eq!(cookie.len(), 16);
+        assert_eq!(for_terminal().unwrap(), cookie);
+
+        // the same pane, seen from another session
+        std::env::set_var("TMUX", "/tmp/tmux-1000/default,4242,3");
+        assert_eq!(for_terminal().unwrap(), cookie);
+
+        std::env::set_var("TMUX_PANE", "%2");
+        assert_ne!(for_terminal().unwrap(), cookie);
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+// Tells whoever launched the genie how starting it went. In daemon mode that
+// is the original process, left waiting on a pipe by detach(); in the
+// foreground it's just the terminal.
+pub struct Readiness(Option<File>);
+
+impl Readiness {
+    pub fn foreground() -> Readiness {
+        Readiness(None)
+    }
+
+    pub fn ready(self, socket_path: &Path) {
+        match self.0 {
+            Some(mut pipe) => {
+                if let Err(err) = writeln!(pipe, "ok {}", socket_path.display()) {
+                    eprintln!("unable to report readiness: {}", err);
+                }
+            }
+            None => println!("{}", socket_path.display()),
+        }
+    }
+
+    pub fn fail(self, err: impl Display) -> ! {
+        match self.0 {
+            Some(mut pipe) => {
+                let _ = writeln!(pipe, "error {}", err);
+            }
+            None => eprintln!("{}", err),
+        }
+        std::process::exit(1)
+    }
+}
+
+// Forks, leaving the parent to wait until the child reports through the
+// returned Readiness: the parent then prints the socket path and exits 0, or
+// prints the child's error and exits 1. Only the child returns. Call before
+// daemonizing, while the process is still single threaded.
+pub fn detach() -> io::Result<Readiness> {
+    // close-on-exec, or the genie's children would hold the parent up too
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for &fd in &fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(reader);
+            Ok(Readiness(Some(writer)))
+        }
+        _ => {
+            drop(writer);
+            wait_for_child(reader)
+        }
+    }
+}
+
+fn wait_for_child(mut reader: File) -> ! {
+    let mut report = String::new();
+    if let Err(err) = reader.read_to_string(&mut report) {
+        eprintln!("error waiting for genie to start: {}", err);
+        std::process::exit(1);
+    }
+
+    let report = report.trim_end();
+    if let Some(socket_path) = report.strip_prefix("ok ") {
+        println!("{}", socket_path);
+        std::process::exit(0);
+    }
+
+    match report.strip_prefix("error ") {
+        Some(err) => eprintln!("{}", err),
+        None => eprintln!("genie exited before binding its socket (see its logfile)"),
+    }
+    std::process::exit(1)
+}
@@ -0,0 +1,148 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+// What a genie says about itself in answer to an info request, as one
+// "key value" line per field and one "arg" line per command line argument.
+#[derive(Serialize)]
+pub struct Info {
+    pub name: String,
+    pub pid: u32,
+    pub label: Option<String>,
+    pub cwd: PathBuf,
+    pub started: u64, // seconds since the epoch
+    pub args: Vec<String>,
+}
+
+impl Info {
+    // Describes the current process, which is the genie called name. Call
+    // after daemonizing, so the pid is right.
+    pub fn current(name: &str, label: Option<&str>) -> Info {
+        Info {
+            name: name.to_string(),
+            pid: std::process::id(),
+            label: label.map(String::from),
+            cwd: std::env::current_dir().unwrap_or_default(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0),
+            args: std::env::args().collect(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("name {}\npid {:x}\n", self.name, self.pid);
+        if let Some(label) = &self.label {
+            out.push_str(&format!("label {}\n", label));
+        }
+        out.push_str(&format!("cwd {}\n", self.cwd.display()));
+        out.push_str(&format!("started {}\n", self.started));
+        for arg in &self.args {
+            out.push_str(&format!("arg {}\n", arg));
+        }
+        out.into_bytes()
+    }
+
+    pub fn parse(response: &[u8]) -> io::Result<Info> {
+        let malformed = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed info response: {}", why),
+            )
+        };
+
+        let response = std::str::from_utf8(response).map_err(|_| malformed("not utf8"))?;
+
+        let mut name = None;
+        let mut pid = None;
+        let mut label = None;
+        let mut cwd = None;
+        let mut started = 0;
+        let mut args = Vec::new();
+        for line in response.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (line, ""),
+            };
+
+            match key {
+                "name" => name = Some(value.to_string()),
+                "pid" => pid = u32::from_str_radix(value, 16).ok(),
+                "label" => label = Some(value.to_string()),
+                "cwd" => cwd = Some(PathBuf::from(value)),
+                "started" => started = value.parse().map_err(|_| malformed("bad start time"))?,
+                "arg" => args.push(value.to_string()),
+                // from some newer genie
+                _ => (),
+            }
+        }
+
+        Ok(Info {
+            name: name.ok_or_else(|| malformed("no name"))?,
+            pid: pid.ok_or_else(|| malformed("no pid"))?,
+            label,
+            cwd: cwd.ok_or_else(|| malformed("no cwd"))?,
+            started,
+            args,
+        })
+    }
+}
+
+// Asks the genie at path to describe itself.
+pub fn query(path: &Path) -> io::Result<Info> {
+    let response = crate::request_within(path, "info\n", crate::QUERY_TIMEOUT)?;
+    Info::parse(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_writes() {
+        let info = Info {
+            name: "watch".to_string(),
+            pid: 0x2a,
+            label: Some("tests for the thing".to_string()),
+            cwd: PathBuf::from("/home/me/src dir"),
+            started: 1_600_000_000,
+            args: vec!["watchg".to_string(), String::new(), "make test".to_string()],
+        };
+        let parsed = Info::parse(&info.to_bytes()).unwrap();
+        assert_eq!(parsed.name, info.name);
+        assert_eq!(parsed.pid, info.pid);
+        assert_eq!(parsed.label, info.label);
+        assert_eq!(parsed.cwd, info.cwd);
+        assert_eq!(parsed.started, info.started);
+        assert_eq!(parsed.args, info.args);
+    }
+
+    #[test]
+    fn ignores_what_it_doesnt_know() {
+        let info = Info::parse(b"name tsc\npid ff\ncwd /\ncolour blue\nbare\n").unwrap();
+        assert_eq!(info.name, "tsc");
+        assert_eq!(info.pid, 255);
+        assert_eq!(info.label, None);
+        assert_eq!(info.started, 0);
+        assert!(info.args.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        for response in [
+            &b""[..],
+            b"pid 2a\ncwd /\n",
+            b"name watch\ncwd /\n",
+            b"name watch\npid 2a\n",
+            b"name watch\npid nope\ncwd /\n",
+            b"name watch\npid 2a\ncwd /\nstarted yesterday\n",
+            b"name \xff\npid 2a\ncwd /\n",
+        ] {
+            let err = Info::parse(response).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", response);
+        }
+    }
+}
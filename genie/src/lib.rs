@@ -1,47 +1,504 @@
+use std::io::{self, prelude::*};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub mod auth;
+pub mod cli;
+pub mod cookie;
+#[cfg(feature = "server")]
+pub mod daemon;
+pub mod info;
+pub mod log;
+pub mod manifest;
+pub mod perms;
+pub mod redact;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod singleton;
+pub mod tcp;
+
 pub const SOCKNAME_PATTERN: &str = r"([a-z0-9]+)\.([a-z0-9]+)\.sock";
 
-pub fn nth(path: &String, n: usize) -> Option<String> {
-    path.split(':').nth(n).map(String::from)
+pub const SUPERVISOR_NAME: &str = "genied";
+pub const SUPERVISED_DIR_PATTERN: &str = r"^genied\.([a-z0-9]+)\.d$";
+
+// a genie bound in the abstract namespace leaves a regular file in its genie
+// directory, in place of the socket, holding this prefix and the abstract name
+pub const REGISTRY_PREFIX: &str = "@";
+
+// Splits GENIE_PATH on colons, keeping tcp://host:port entries (with the
+// host possibly a bracketed ipv6 address) in one piece.
+pub fn split_path(path: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut pieces = path.split(':');
+    while let Some(piece) = pieces.next() {
+        if piece != "tcp" {
+            entries.push(piece.to_string());
+            continue;
+        }
+
+        // the host follows, then the port
+        let mut entry = piece.to_string();
+        let mut in_host = true;
+        for piece in pieces.by_ref() {
+            entry.push(':');
+            entry.push_str(piece);
+            if !in_host {
+                break;
+            }
+            in_host = entry.contains('[') && !entry.contains(']');
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+pub fn nth(path: &str, n: usize) -> Option<String> {
+    split_path(path).into_iter().nth(n)
+}
+
+// a genie's entry in one of the GENIE_PATH directories
+pub struct Entry {
+    pub path: PathBuf,
+    pub level: usize,
+    pub name: String,
+    pub pid: String,
 }
 
-pub fn find(
-    path: &String,
-    name: &String,
-    num: &Option<String>,
-) -> Option<(std::path::PathBuf, usize)> {
+// Lists every genie entry along path, in path order, including those of
+// genies run by genied.
+pub fn entries(path: &str) -> Vec<Entry> {
     let re = regex::Regex::new(SOCKNAME_PATTERN).unwrap();
-    for (i, dir) in path.split(':').enumerate() {
-        let dir = match std::fs::read_dir(dir) {
-            Err(_) => continue,
-            Ok(dir) => dir,
+    let mut entries = Vec::new();
+    for (level, dir) in split_path(path).iter().enumerate() {
+        if tcp::is_tcp(dir) {
+            continue;
+        }
+        entries_in(Path::new(dir), level, &re, &mut entries);
+    }
+    entries
+}
+
+fn entries_in(dir: &Path, level: usize, re: &regex::Regex, entries: &mut Vec<Entry>) {
+    let dir = match std::fs::read_dir(dir) {
+        Err(_) => return,
+        Ok(dir) => dir,
+    };
+
+    let mut supervised = Vec::new();
+    for entry in dir {
+        let entry = match entry {
+            Err(err) => {
+                eprintln!("error retrieving directory entry: {}", err);
+                continue;
+            }
+
+            Ok(entry) => entry,
         };
 
-        for entry in dir {
-            let entry = match entry {
-                Err(err) => {
-                    eprintln!("error retrieving directory entry: {}", err);
-                    continue;
-                }
+        let sockname = entry
+            .file_name()
+            .into_string()
+            .expect("unconvertable file name");
+        if is_supervised_dir(&sockname) {
+            supervised.push(entry.path());
+            continue;
+        }
+
+        let captures = match re.captures(&sockname) {
+            None => continue,
+            Some(captures) => captures,
+        };
 
-                Ok(entry) => entry,
-            };
-
-            let sockname = entry
-                .file_name()
-                .into_string()
-                .expect("unconvertable file name");
-            let captures = match re.captures(&sockname) {
-                None => continue,
-                Some(captures) => captures,
-            };
-
-            if *name == captures[1].to_string()
-                && (num.is_none() || *num == Some(captures[2].to_string()))
-            {
-                return Some((entry.path(), i));
+        entries.push(Entry {
+            path: entry.path(),
+            level,
+            name: captures[1].to_string(),
+            pid: captures[2].to_string(),
+        });
+    }
+
+    // genies run by genied live a level down
+    for dir in supervised {
+        entries_in(&dir, level, re, entries);
+    }
+}
+
+pub fn find(path: &str, name: &str, num: &Option<String>) -> Option<(std::path::PathBuf, usize)> {
+    entries(path)
+        .into_iter()
+        .find(|entry| entry.name == name && (num.is_none() || *num == Some(entry.pid.clone())))
+        .map(|entry| (entry.path, entry.level))
+}
+
+pub fn sockname(genie_dir: &str, name: &str, pid: u32) -> PathBuf {
+    PathBuf::from(format!("{}/{}.{:x}.sock", genie_dir, name, pid))
+}
+
+// where genied with the given pid keeps the sockets of the genies it runs
+pub fn supervised_dir(genie_dir: &str, pid: u32) -> PathBuf {
+    PathBuf::from(format!("{}/{}.{:x}.d", genie_dir, SUPERVISOR_NAME, pid))
+}
+
+pub fn is_supervised_dir(filename: &str) -> bool {
+    regex::Regex::new(SUPERVISED_DIR_PATTERN)
+        .unwrap()
+        .is_match(filename)
+}
+
+// genied answers multipoll with a block for each genie that had something to
+// say: a "name pid length" line, then that many bytes of its response
+pub fn write_multi(out: &mut Vec<u8>, name: &str, pid: &str, response: &[u8]) {
+    out.extend_from_slice(format!("{} {} {}\n", name, pid, response.len()).as_bytes());
+    out.extend_from_slice(response);
+}
+
+pub fn split_multi(mut response: &[u8]) -> io::Result<Vec<(String, String, Vec<u8>)>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed multipoll response");
+
+    let mut blocks = Vec::new();
+    while !response.is_empty() {
+        let newline = response
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(malformed)?;
+        let header = std::str::from_utf8(&response[..newline]).map_err(|_| malformed())?;
+        let mut fields = header.split(' ');
+        let (name, pid, length) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(pid), Some(length)) => {
+                (name, pid, length.parse::<usize>().map_err(|_| malformed())?)
             }
+            _ => return Err(malformed()),
+        };
+
+        let body = &response[newline + 1..];
+        if body.len() < length {
+            return Err(malformed());
         }
+
+        blocks.push((name.to_string(), pid.to_string(), body[..length].to_vec()));
+        response = &body[length..];
+    }
+
+    Ok(blocks)
+}
+
+// Takes an exclusive flock on file without waiting, returning false if
+// someone else holds it.
+pub fn try_lock(file: &std::fs::File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+// how long a genie gets to answer a question about itself, like info or peek
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// a genie answers peek with one of these, saying whether a poll with the same
+// cookie would have something to show, without counting as one
+pub const UNSEEN: &[u8] = b"new\n";
+pub const SEEN: &[u8] = b"seen\n";
+
+// Asks the genie at path whether it has output the holder of cookie hasn't
+// polled yet; None if it predates peek requests.
+pub fn peek(path: &Path, cookie: &str) -> io::Result<Option<bool>> {
+    let response = request_within(path, &format!("peek\n{}\n", cookie), QUERY_TIMEOUT)?;
+    Ok(unseen(&response))
+}
+
+pub(crate) fn unseen(response: &[u8]) -> Option<bool> {
+    match response {
+        UNSEEN => Some(true),
+        SEEN => Some(false),
+        _ => None,
+    }
+}
+
+// Binds the listening socket for this process' genie, returning it along with
+// the entry left in genie_dir. With abstract_ns, or when the socket path is
+// too long for sockaddr_un, the socket goes in the Linux abstract namespace
+// and the entry is a registry file naming it. Either way the entry is
+// private to the current user, or shared with group if one is given.
+pub fn bind(
+    genie_dir: &str,
+    name: &str,
+    abstract_ns: bool,
+    group: Option<u32>,
+) -> io::Result<(UnixListener, PathBuf)> {
+    let (listener, path) = match group {
+        Some(gid) => {
+            perms::check_shared_dir(Path::new(genie_dir), gid)?;
+            bind_entry(genie_dir, name, abstract_ns, 0o660)?
+        }
+        None => {
+            perms::check_owner(Path::new(genie_dir))?;
+            bind_entry(genie_dir, name, abstract_ns, 0o600)?
+        }
+    };
+
+    if let Some(gid) = group {
+        perms::share(&path, gid)?;
     }
 
-    None
+    Ok((listener, path))
+}
+
+// Binds, leaving an entry with the given mode: set once it's there, as the
+// umask is the whole process' to change, not just this thread's.
+fn bind_entry(
+    genie_dir: &str,
+    name: &str,
+    abstract_ns: bool,
+    mode: u32,
+) -> io::Result<(UnixListener, PathBuf)> {
+    let pid = std::process::id();
+    let path = sockname(genie_dir, name, pid);
+
+    if !abstract_ns {
+        match UnixListener::bind(&path) {
+            Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                eprintln!(
+                    "socket path {} is too long, binding in the abstract namespace",
+                    path.display()
+                );
+            }
+            result => {
+                let listener = result?;
+                if let Err(err) =
+                    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(err);
+                }
+                return Ok((listener, path));
+            }
+        }
+    }
+
+    let abstract_name = format!("genie/{}/{}.{:x}", perms::uid(), name, pid);
+    let listener = bind_abstract(&abstract_name)?;
+
+    let mut registry = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&path)?;
+    registry.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    writeln!(registry, "{}{}", REGISTRY_PREFIX, abstract_name)?;
+
+    Ok((listener, path))
+}
+
+// the first file descriptor passed by a socket activating launcher
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// Takes the listening socket handed over by a socket activating launcher
+// (systemd, or genie_activate) through LISTEN_PID and LISTEN_FDS, if there is
+// one, along with its path in the genie directory.
+pub fn activated() -> io::Result<Option<(UnixListener, PathBuf)>> {
+    let fds = match std::env::var("LISTEN_FDS") {
+        Err(_) => return Ok(None),
+        Ok(fds) => fds,
+    };
+
+    if let Ok(pid) = std::env::var("LISTEN_PID") {
+        if pid != std::process::id().to_string() {
+            return Ok(None);
+        }
+    }
+
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    match fds.parse::<u32>() {
+        Ok(0) => return Ok(None),
+        Ok(1) => (),
+        Ok(n) => eprintln!("passed {} sockets, only listening on the first", n),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("LISTEN_FDS is not a number: {}", fds),
+            ))
+        }
+    }
+
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    let listener = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    let path = match listener.local_addr()?.as_pathname() {
+        Some(path) => path.to_path_buf(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "passed socket has no path in a genie directory",
+            ))
+        }
+    };
+
+    Ok(Some((listener, path)))
+}
+
+// Connects to the genie at path, following registry files into the abstract
+// namespace. Entries, directories and genies belonging to other users are
+// refused unless GENIE_INSECURE is set.
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    perms::check_entry(path)?;
+
+    if std::fs::symlink_metadata(path)?.is_file() {
+        let stream = connect_abstract(&read_registry(path)?)?;
+        perms::check_peer(&stream, path)?;
+        Ok(stream)
+    } else {
+        UnixStream::connect(path)
+    }
+}
+
+// Sends a single request to the genie at path, returning its full response.
+pub fn request(path: &Path, request: &str) -> io::Result<Vec<u8>> {
+    send(connect(path)?, request)
+}
+
+// Like request, but gives up on a genie that doesn't answer within timeout.
+pub fn request_within(path: &Path, request: &str, timeout: Duration) -> io::Result<Vec<u8>> {
+    let stream = connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    send(stream, request)
+}
+
+fn send(mut stream: UnixStream, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+pub fn read_registry(path: &Path) -> io::Result<String> {
+    let contents = std::fs::read_to_string(path)?;
+    match contents.trim_end().strip_prefix(REGISTRY_PREFIX) {
+        Some(abstract_name) if !abstract_name.is_empty() => Ok(abstract_name.to_string()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a genie registry file", path.display()),
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(abstract_name: &str) -> io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+    UnixListener::bind_addr(&addr)
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(abstract_name: &str) -> io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+    UnixStream::connect_addr(&addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_abstract_name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::other(
+        "the abstract socket namespace is only available on linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_abstract_name: &str) -> io::Result<UnixStream> {
+    Err(io::Error::other(
+        "the abstract socket namespace is only available on linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_path_on_colons() {
+        assert_eq!(split_path("/a:/b/c"), vec!["/a", "/b/c"]);
+        assert_eq!(split_path("/a"), vec!["/a"]);
+        assert_eq!(split_path(""), vec![""]);
+    }
+
+    #[test]
+    fn keeps_tcp_entries_whole() {
+        assert_eq!(
+            split_path("/a:tcp://example.com:7077:/b"),
+            vec!["/a", "tcp://example.com:7077", "/b"]
+        );
+        assert_eq!(
+            split_path("tcp://[::1]:7077:tcp://[fe80::1:2]:80"),
+            vec!["tcp://[::1]:7077", "tcp://[fe80::1:2]:80"]
+        );
+        // one missing its port takes what follows, as the port
+        assert_eq!(split_path("tcp://host:/a"), vec!["tcp://host:/a"]);
+        assert_eq!(split_path("tcp://host"), vec!["tcp://host"]);
+        assert_eq!(split_path("/a:tcp"), vec!["/a", "tcp"]);
+    }
+
+    #[test]
+    fn reads_registry_files() {
+        let path = std::env::temp_dir().join(format!("genie-test.{}.sock", std::process::id()));
+        std::fs::write(&path, format!("{}genie/1000/watch.2a\n", REGISTRY_PREFIX)).unwrap();
+        let abstract_name = read_registry(&path);
+        for contents in &["", "@", "\n", "watch.2a\n"] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(read_registry(&path).is_err(), "{:?}", contents);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(abstract_name.unwrap(), "genie/1000/watch.2a");
+    }
+
+    #[test]
+    fn splits_what_write_multi_writes() {
+        let mut out = Vec::new();
+        write_multi(&mut out, "watch", "2a", b"one\ntwo\n");
+        write_multi(&mut out, "tsc", "3b", b"");
+        write_multi(&mut out, "msg", "4c", b"a b 3\n\0");
+        assert_eq!(
+            split_multi(&out).unwrap(),
+            vec![
+                (
+                    "watch".to_string(),
+                    "2a".to_string(),
+                    b"one\ntwo\n".to_vec()
+                ),
+                ("tsc".to_string(), "3b".to_string(), Vec::new()),
+                ("msg".to_string(), "4c".to_string(), b"a b 3\n\0".to_vec()),
+            ]
+        );
+        assert!(split_multi(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_multipoll_responses() {
+        for response in [
+            &b"watch 2a 5"[..],
+            b"watch 2a\nhello",
+            b"watch 2a x\nhello",
+            b"watch 2a 6\nhello",
+            b"watch 2a 5\nhello\nstray",
+            b"\xff 2a 1\nx",
+        ] {
+            assert!(split_multi(response).is_err(), "{:?}", response);
+        }
+    }
 }
@@ -0,0 +1,377 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, MutexGuard, PoisonError, TryLockError,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the default level, and the level genied passes on to the genies it runs
+pub const LEVEL_VAR: &str = "GENIE_LOG";
+
+pub const LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+// a log file is rotated once it would grow past MAX_SIZE, keeping KEEP old
+// files alongside it as file.1 (the newest) to file.KEEP
+pub const MAX_SIZE: u64 = 1 << 20;
+pub const KEEP: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_env() -> Level {
+        std::env::var(LEVEL_VAR)
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(Level::Info)
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Level, String> {
+        match level {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!(
+                "unknown log level {} (expected one of {})",
+                level,
+                LEVELS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(LEVELS[*self as usize])
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // chosen by us rather than the user, so ours to remove
+    default: bool,
+}
+
+impl LogFile {
+    fn open(path: &Path, default: bool) -> io::Result<LogFile> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            default,
+        })
+    }
+
+    // Where the file is now: a default log may have been moved along with
+    // its genie's socket, as genie promote does.
+    fn current_path(&self) -> PathBuf {
+        std::fs::read_link(format!("/proc/self/fd/{}", self.file.as_raw_fd()))
+            .ok()
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| self.path.clone())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.path = self.current_path();
+        for n in (1..KEEP).rev() {
+            let _ = std::fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+        }
+        std::fs::rename(&self.path, rotated(&self.path, 1))?;
+
+        *self = LogFile::open(&self.path, self.default)?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_SIZE {
+            if let Err(err) = self.rotate() {
+                eprintln!("unable to rotate {}: {}", self.path.display(), err);
+            }
+        }
+
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+struct Logger {
+    name: String,
+    level: Level,
+    // until there is one, we log to stderr
+    file: Option<LogFile>,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+// set once a thread has panicked, maybe holding a lock it will never let go
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+// how long, after a panic, a lock is tried before it's given up on
+const PANIC_LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Notes that a thread has panicked, so that from then on locks are only
+// tried: it may have panicked holding one, and our mutexes aren't reentrant.
+pub fn panicked() {
+    PANICKED.store(true, Ordering::SeqCst);
+}
+
+// Locks mutex, whether or not a panic poisoned it. After a panic, gives up
+// on one still held after PANIC_LOCK_TIMEOUT, as one held by the thread that
+// panicked would be: better a line or a cleanup missed than a hang.
+pub fn lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    if !PANICKED.load(Ordering::SeqCst) {
+        return Some(mutex.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+
+    let deadline = Instant::now() + PANIC_LOCK_TIMEOUT;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => return Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(1))
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+// Sets up logging for the genie called name, to logfile if given and to
+// stderr otherwise. A daemonized genie without a logfile should call
+// log_beside once it has bound its socket.
+pub fn init(name: &str, level: Level, logfile: Option<&Path>) -> io::Result<()> {
+    let file = match logfile {
+        Some(logfile) => Some(LogFile::open(logfile, false)?),
+        None => None,
+    };
+
+    *LOGGER.lock().unwrap() = Some(Logger {
+        name: name.to_string(),
+        level,
+        file,
+    });
+    Ok(())
+}
+
+// socket_path with its extension swapped, e.g. watch.1a2b.log
+pub fn logname(socket_path: &Path) -> PathBuf {
+    socket_path.with_extension("log")
+}
+
+// where the nth newest of the older logs rotated out of path is kept, up to
+// KEEP of them
+pub fn rotated(path: &Path, n: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), n))
+}
+
+// Starts logging next to socket_path, unless we were given a logfile.
+pub fn log_beside(socket_path: &Path) -> io::Result<()> {
+    let mut logger = LOGGER.lock().unwrap();
+    if let Some(logger) = logger.as_mut() {
+        if logger.file.is_none() {
+            logger.file = Some(LogFile::open(&logname(socket_path), true)?);
+        }
+    }
+    Ok(())
+}
+
+// Removes the log we put next to our socket, for a genie going down cleanly;
+// one that failed keeps its log for whoever comes looking.
+pub fn discard_default() {
+    let mut logger = lock(&LOGGER);
+    if let Some(logger) = logger.as_mut().and_then(|logger| logger.as_mut()) {
+        if let Some(file) = logger.file.take() {
+            if file.default {
+                let _ = std::fs::remove_file(file.current_path());
+            } else {
+                logger.file = Some(file);
+            }
+        }
+    }
+}
+
+// Logs at level, to stderr if the logger can't be had, as after a panic.
+pub fn write(level: Level, args: fmt::Arguments) {
+    let mut logger = lock(&LOGGER);
+    let logger = logger.as_mut().and_then(|logger| logger.as_mut());
+
+    let (name, max) = match logger.as_deref() {
+        Some(logger) => (logger.name.as_str(), logger.level),
+        None => ("genie", Level::Info),
+    };
+    if level > max {
+        return;
+    }
+
+    let line = format!(
+        "{} {:<5} {}.{:x}: {}\n",
+        timestamp(),
+        level,
+        name,
+        std::process::id(),
+        args
+    );
+    emit(logger, &line);
+}
+
+// Passes on a line already logged by someone else, like a genie run by
+// genied.
+pub fn relay(line: &str) {
+    let mut logger = lock(&LOGGER);
+    let logger = logger.as_mut().and_then(|logger| logger.as_mut());
+    emit(logger, &format!("{}\n", line));
+}
+
+fn emit(logger: Option<&mut Logger>, line: &str) {
+    match logger.and_then(|logger| logger.file.as_mut()) {
+        Some(file) => file.write(line),
+        None => eprint!("{}", line),
+    }
+}
+
+// UTC, to the millisecond, e.g. 2020-12-06T21:04:05.123Z
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil(secs / 86400);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+// the date days after the epoch, from Howard Hinnant's civil_from_days
+fn civil(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log::write($crate::log::Level::Trace, format_args!($($arg)*)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gives_up_on_a_lock_held_through_a_panic() {
+        let mutex = Mutex::new(());
+        let held = mutex.lock().unwrap();
+        panicked();
+        assert!(lock(&mutex).is_none());
+        drop(held);
+        assert!(lock(&mutex).is_some());
+    }
+
+    #[test]
+    fn dates_days_after_the_epoch() {
+        assert_eq!(civil(0), (1970, 1, 1));
+        assert_eq!(civil(789), (1972, 2, 29));
+        assert_eq!(civil(11016), (2000, 2, 29));
+        assert_eq!(civil(11017), (2000, 3, 1));
+        assert_eq!(civil(19782), (2024, 2, 29));
+        assert_eq!(civil(47540), (2100, 2, 28));
+        assert_eq!(civil(47541), (2100, 3, 1));
+    }
+
+    #[test]
+    fn rotates_once_past_the_size_limit() {
+        let dir = std::env::temp_dir().join(format!("genie-log.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("watch.log");
+        let size = |path: &Path| std::fs::metadata(path).map(|meta| meta.len()).ok();
+
+        let mut log = LogFile::open(&path, false).unwrap();
+        log.write("first\n");
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(MAX_SIZE - 1)
+            .unwrap();
+        log.size = MAX_SIZE - 1;
+        log.write("\n");
+        assert_eq!(size(&path), Some(MAX_SIZE));
+        assert_eq!(size(&rotated(&path, 1)), None);
+
+        // the full file goes to .1, then along and out past .KEEP
+        for _ in 0..=KEEP {
+            log.size = MAX_SIZE;
+            log.write("next\n");
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "next\n");
+        }
+        assert_eq!(size(&rotated(&path, 1)), Some(5));
+        assert_eq!(size(&rotated(&path, KEEP)), Some(5));
+        assert_eq!(size(&rotated(&path, KEEP + 1)), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+pub const MANIFEST_NAME: &str = "Geniefile";
+
+// A project's Geniefile, naming the genies to run in it by label:
+//
+//     [genies.types]
+//     command = "tscg"
+//     args = ["-p", "tsconfig.json"]
+//
+//     [genies.tests]
+//     command = "watchg"
+//     args = ["-n", "30", "make", "test"]
+#[derive(Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub genies: BTreeMap<String, Spec>,
+}
+
+#[derive(Deserialize)]
+pub struct Spec {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+// Looks for a Geniefile in dir and then in each of its parents.
+pub fn locate(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(MANIFEST_NAME))
+        .find(|path| path.is_file())
+}
+
+pub fn load(path: &Path) -> io::Result<Manifest> {
+    let contents = std::fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })
+}
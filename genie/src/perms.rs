@@ -0,0 +1,373 @@
+use std::ffi::{CStr, CString};
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+// set (to anything but "" or "0") to talk to genies owned by other users
+pub const INSECURE_VAR: &str = "GENIE_INSECURE";
+
+// the groups, by name or number and separated by commas, whose shared
+// directories we trust genies left by other members in
+pub const SHARED_GROUPS_VAR: &str = "GENIE_SHARED_GROUPS";
+
+pub fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+// the groups we belong to, including our primary group
+pub fn groups() -> Vec<u32> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getgid() });
+    groups
+}
+
+// Runs a getgr*_r lookup, growing the buffer until the entry fits, and returns
+// the group's gid and member names.
+fn lookup_group<F>(mut get: F) -> io::Result<Option<(u32, Vec<String>)>>
+where
+    F: FnMut(*mut libc::group, &mut [libc::c_char], *mut *mut libc::group) -> libc::c_int,
+{
+    let mut buffer = vec![0; 1024];
+    loop {
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        match get(&mut group, &mut buffer, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let mut members = Vec::new();
+                let mut member = group.gr_mem;
+                while !member.is_null() && !unsafe { *member }.is_null() {
+                    let name = unsafe { CStr::from_ptr(*member) };
+                    members.push(name.to_string_lossy().into_owned());
+                    member = unsafe { member.add(1) };
+                }
+                return Ok(Some((group.gr_gid, members)));
+            }
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(2 * buffer.len(), 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+// the gid of group, given by name or number
+pub fn group_id(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad group name"))?;
+    let found = lookup_group(|group, buffer, result| unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            result,
+        )
+    })?;
+
+    match found {
+        Some((gid, _)) => Ok(gid),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such group: {}", group),
+        )),
+    }
+}
+
+fn user_name(uid: u32) -> io::Result<Option<String>> {
+    let mut buffer = vec![0; 1024];
+    loop {
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+                return Ok(Some(name.to_string_lossy().into_owned()));
+            }
+            libc::ERANGE if buffer.len() < 1 << 20 => buffer.resize(2 * buffer.len(), 0),
+            err => return Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+// whether the user uid, whose primary group is primary_gid, belongs to gid
+fn in_group(uid: u32, primary_gid: u32, gid: u32) -> io::Result<bool> {
+    if primary_gid == gid {
+        return Ok(true);
+    }
+
+    let name = match user_name(uid)? {
+        Some(name) => name,
+        None => return Ok(false),
+    };
+    let found = lookup_group(|group, buffer, result| unsafe {
+        libc::getgrgid_r(gid, group, buffer.as_mut_ptr(), buffer.len(), result)
+    })?;
+
+    Ok(found.is_some_and(|(_, members)| members.contains(&name)))
+}
+
+pub fn insecure() -> bool {
+    match std::env::var(INSECURE_VAR) {
+        Ok(value) => !value.is_empty() && value != "0",
+        Err(_) => false,
+    }
+}
+
+// whether SHARED_GROUPS_VAR lists gid
+pub fn trusts_group(gid: u32) -> bool {
+    std::env::var(SHARED_GROUPS_VAR).is_ok_and(|groups| {
+        groups
+            .split(',')
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .any(|group| group_id(group).is_ok_and(|id| id == gid))
+    })
+}
+
+fn refuse(path: &Path, why: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!(
+            "refusing to use {}: {} (set {}=1 to override)",
+            path.display(),
+            why,
+            INSECURE_VAR
+        ),
+    )
+}
+
+pub fn check_owner(path: &Path) -> io::Result<()> {
+    if insecure() {
+        return Ok(());
+    }
+
+    let owner = std::fs::symlink_metadata(path)?.uid();
+    if owner != uid() {
+        return Err(refuse(
+            path,
+            format!("owned by uid {}, not uid {}", owner, uid()),
+        ));
+    }
+
+    Ok(())
+}
+
+// A directory shared by the members of gid, where any of them may run
+// genies: it must belong to the group, we must be in it, and nobody outside
+// it may write there.
+pub fn check_shared_dir(path: &Path, gid: u32) -> io::Result<()> {
+    if insecure() {
+        return Ok(());
+    }
+
+    let meta = std::fs::symlink_metadata(path)?;
+    if meta.gid() != gid {
+        return Err(refuse(
+            path,
+            format!("group is gid {}, not gid {}", meta.gid(), gid),
+        ));
+    }
+    if !groups().contains(&gid) {
+        return Err(refuse(path, format!("we are not in gid {}", gid)));
+    }
+    if meta.mode() & 0o002 != 0 {
+        return Err(refuse(path, "world-writable".to_string()));
+    }
+
+    Ok(())
+}
+
+// an entry left by another member of a group we're in, in a directory shared
+// by that group, which we've said we trust
+fn shared(dir: &Metadata, entry: &Metadata) -> bool {
+    dir.gid() == entry.gid()
+        && groups().contains(&dir.gid())
+        && dir.mode() & 0o002 == 0
+        && entry.mode() & 0o002 == 0
+        && trusts_group(dir.gid())
+}
+
+// a genie entry can only be trusted if both it and the directory holding it
+// belong to us, or if both belong to a group we share with its owner and
+// SHARED_GROUPS_VAR lists
+pub fn check_entry(path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        if !insecure() {
+            let dir_meta = std::fs::symlink_metadata(dir)?;
+            let entry_meta = std::fs::symlink_metadata(path)?;
+            if shared(&dir_meta, &entry_meta) {
+                return Ok(());
+            }
+        }
+        check_owner(dir)?;
+    }
+    check_owner(path)
+}
+
+// the uid and gid of whoever is on the other end of the unix socket fd
+#[cfg(target_os = "linux")]
+pub fn peer_cred(fd: RawFd) -> io::Result<(u32, u32)> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((cred.uid, cred.gid))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn peer_cred(fd: RawFd) -> io::Result<(u32, u32)> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((uid, gid))
+}
+
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    peer_cred(stream.as_raw_fd()).map(|(uid, _)| uid)
+}
+
+// Checks, in a genie, that the client on the other end of fd may talk to us:
+// it must be us, or a member of the group we're shared with. Returns the
+// client's uid.
+pub fn check_client(fd: RawFd, group: Option<u32>) -> io::Result<u32> {
+    let (peer, peer_gid) = peer_cred(fd)?;
+    if insecure() || peer == uid() {
+        return Ok(peer);
+    }
+
+    if let Some(gid) = group {
+        if in_group(peer, peer_gid, gid)? {
+            return Ok(peer);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("refusing client uid {}", peer),
+    ))
+}
+
+// abstract sockets carry no permissions, so whoever is listening on the name
+// has to be checked instead: it must be whoever left the registry file
+pub fn check_peer(stream: &UnixStream, path: &Path) -> io::Result<()> {
+    if insecure() {
+        return Ok(());
+    }
+
+    let peer = peer_uid(stream)?;
+    let owner = std::fs::symlink_metadata(path)?.uid();
+    if peer != owner {
+        return Err(refuse(
+            path,
+            format!("genie is run by uid {}, not uid {}", peer, owner),
+        ));
+    }
+
+    Ok(())
+}
+
+// hands path over to group gid, for a genie shared with it
+pub fn share(path: &Path, gid: u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad path"))?;
+    if unsafe { libc::lchown(path.as_ptr(), libc::uid_t::MAX, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn dir(name: &str, mode: u32) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("genie-perms.{}.{}", std::process::id(), name));
+        let _ = std::fs::remove_dir(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(mode)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn trusts_only_our_own_dirs() {
+        let ours = dir("ours", 0o700);
+        assert!(check_owner(&ours).is_ok());
+
+        // root can give one away; anyone else has / to try
+        let theirs = if uid() == 0 {
+            let theirs = dir("theirs", 0o700);
+            std::os::unix::fs::lchown(&theirs, Some(65534), None).unwrap();
+            theirs
+        } else {
+            PathBuf::from("/")
+        };
+        let refused = check_owner(&theirs);
+
+        std::fs::remove_dir(&ours).unwrap();
+        if theirs != Path::new("/") {
+            std::fs::remove_dir(&theirs).unwrap();
+        }
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn trusts_shared_dirs_of_our_group_only() {
+        let gid = unsafe { libc::getgid() };
+        let shared = dir("shared", 0o770);
+        let world = dir("world", 0o777);
+
+        let ok = check_shared_dir(&shared, gid);
+        // none of our groups is u32::MAX - 1
+        let other_group = check_shared_dir(&shared, u32::MAX - 1);
+        let world_writable = check_shared_dir(&world, gid);
+
+        std::fs::remove_dir(&shared).unwrap();
+        std::fs::remove_dir(&world).unwrap();
+        assert!(ok.is_ok());
+        assert!(other_group.is_err());
+        assert!(world_writable
+            .unwrap_err()
+            .to_string()
+            .contains("world-writable"));
+    }
+}
@@ -0,0 +1,60 @@
+use regex::bytes::Regex;
+
+// what a match is replaced with
+pub const REDACTED: &str = "[redacted]";
+
+// Strips whatever matches any of its patterns, like secrets, from output
+// before a genie serves it.
+#[derive(Clone)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Redactor, regex::Error> {
+        Ok(Redactor {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern.as_ref()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn apply(&self, output: &[u8]) -> Vec<u8> {
+        let mut output = output.to_vec();
+        for pattern in &self.patterns {
+            output = pattern
+                .replace_all(&output, REDACTED.as_bytes())
+                .into_owned();
+        }
+        output
+    }
+
+    pub fn apply_str(&self, output: &str) -> String {
+        String::from_utf8_lossy(&self.apply(output.as_bytes())).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_every_match_of_every_pattern() {
+        let redactor = Redactor::new(&["token=[0-9a-f]+", "hunter2"]).unwrap();
+        assert_eq!(
+            redactor.apply_str("token=abc1 ok token=ff hunter2\n"),
+            "[redacted] ok [redacted] [redacted]\n"
+        );
+        assert_eq!(redactor.apply(b"\xffclean"), b"\xffclean");
+        assert_eq!(
+            Redactor::new::<&str>(&[]).unwrap().apply(b"as is"),
+            b"as is"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        assert!(Redactor::new(&["(unclosed"]).is_err());
+    }
+}
@@ -0,0 +1,953 @@
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg, ArgMatches};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    process::{Child, Command},
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+use crate::auth::Auth;
+use crate::daemon::Readiness;
+use crate::singleton::Lock;
+
+// the entry to remove from the genie directory on the way out
+static SOCKET_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// set once exit() has started
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+// files and directories to remove once the children are gone
+static PATHS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// process groups of running children, each led by the child we spawned
+struct Groups {
+    pgids: Vec<i32>,
+    // set by terminate_children(), after which no new children may be
+    // spawned
+    closed: bool,
+}
+
+static GROUPS: Mutex<Groups> = Mutex::new(Groups {
+    pgids: Vec::new(),
+    closed: false,
+});
+
+// how long children get between SIGTERM and SIGKILL
+pub const KILL_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Arranges for socket_path (if it is ours to remove, which a socket passed
+// in by a launcher isn't) to be removed and children to be terminated however
+// the genie goes down: on exit(), on SIGTERM, SIGINT or SIGHUP, or on a panic
+// in any thread. Must be called from within the tokio runtime.
+pub fn install(socket_path: Option<PathBuf>) {
+    *SOCKET_PATH.lock().unwrap() = socket_path;
+
+    // the panicking thread may hold any of our locks, or the logger's, so
+    // from here on they're only tried
+    std::panic::set_hook(Box::new(|info| {
+        crate::log::panicked();
+        crate::error!("last error: {}", info);
+        exit(101)
+    }));
+
+    let mut term = signal(SignalKind::terminate()).expect("unable to handle SIGTERM");
+    let mut int = signal(SignalKind::interrupt()).expect("unable to handle SIGINT");
+    let mut hup = signal(SignalKind::hangup()).expect("unable to handle SIGHUP");
+
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = term.recv() => "SIGTERM",
+            _ = int.recv() => "SIGINT",
+            _ = hup.recv() => "SIGHUP",
+        };
+
+        crate::info!("received {}, shutting down", name);
+        exit_from_task(0).await
+    });
+}
+
+pub fn cleanup() {
+    let socket_path = crate::log::lock(&SOCKET_PATH).and_then(|mut guard| guard.take());
+
+    if let Some(socket_path) = socket_path.as_deref().and_then(current_entry) {
+        for path in [crate::auth::token_path(&socket_path), socket_path] {
+            if let Err(err) = std::fs::remove_file(&path) {
+                crate::warn!("unable to remove {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+// Where our entry, once at socket_path, is now: someone may have moved it
+// along GENIE_PATH, as genie promote does.
+fn current_entry(socket_path: &Path) -> Option<PathBuf> {
+    if std::fs::symlink_metadata(socket_path).is_ok() {
+        return Some(socket_path.to_path_buf());
+    }
+
+    let filename = socket_path.file_name()?;
+    let path = std::env::var("GENIE_PATH").ok()?;
+    crate::split_path(&path)
+        .into_iter()
+        .filter(|dir| !crate::tcp::is_tcp(dir))
+        .map(|dir| Path::new(&dir).join(filename))
+        .find(|path| std::fs::symlink_metadata(path).is_ok())
+}
+
+// Moves our entry to dir, for when it can't simply be renamed there, as
+// across filesystems: binds a new socket in dir, copies our token beside it
+// and removes the old entry and token. Whoever was listening on the old
+// socket should listen on the new one from then on.
+pub fn rebind(
+    dir: &str,
+    name: &str,
+    group: Option<u32>,
+) -> io::Result<(std::os::unix::net::UnixListener, PathBuf)> {
+    let old = SOCKET_PATH
+        .lock()
+        .unwrap()
+        .as_deref()
+        .and_then(current_entry)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "our socket isn't ours to move")
+        })?;
+
+    let (listener, new) = crate::bind(dir, name, false, group)?;
+    if let Err(err) = crate::auth::copy_token(&old, &new) {
+        let _ = std::fs::remove_file(&new);
+        return Err(err);
+    }
+
+    *SOCKET_PATH.lock().unwrap() = Some(new.clone());
+    for path in [crate::auth::token_path(&old), old] {
+        if let Err(err) = std::fs::remove_file(&path) {
+            crate::warn!("unable to remove {}: {}", path.display(), err);
+        }
+    }
+
+    listener.set_nonblocking(true)?;
+    Ok((listener, new))
+}
+
+// Spawns command as the leader of a new process group, so that it and
+// anything it starts are terminated along with the genie. Call
+// forget_group() once the child has been waited for.
+pub fn spawn_group(command: &mut Command) -> io::Result<Child> {
+    // held until the group is recorded, so terminate_children() can't miss it
+    let mut groups = match crate::log::lock(&GROUPS) {
+        Some(groups) if !groups.closed => groups,
+        _ => return Err(io::Error::other("genie is shutting down")),
+    };
+
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    if let Some(pid) = child.id() {
+        groups.pgids.push(pid as i32);
+    }
+
+    Ok(child)
+}
+
+pub fn forget_group(pid: Option<u32>) {
+    if let (Some(pid), Some(mut groups)) = (pid, crate::log::lock(&GROUPS)) {
+        groups.pgids.retain(|&pgid| pgid != pid as i32);
+    }
+}
+
+fn group_alive(pgid: i32) -> bool {
+    // reap the leader if it's done, or it would keep the group alive as a
+    // zombie
+    unsafe { libc::waitpid(pgid, std::ptr::null_mut(), libc::WNOHANG) };
+    unsafe { libc::kill(-pgid, 0) == 0 }
+}
+
+// SIGTERM every child process group, then SIGKILL whatever is left after
+// KILL_TIMEOUT, reaping the leaders.
+pub fn terminate_children() {
+    let mut groups = match crate::log::lock(&GROUPS) {
+        Some(mut groups) => {
+            groups.closed = true;
+            std::mem::take(&mut groups.pgids)
+        }
+        None => return,
+    };
+
+    for &pgid in &groups {
+        unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    }
+
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    loop {
+        groups.retain(|&pgid| group_alive(pgid));
+        if groups.is_empty() {
+            return;
+        }
+
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    for &pgid in &groups {
+        crate::warn!("process group {} ignored SIGTERM, killing it", pgid);
+        unsafe {
+            libc::kill(-pgid, libc::SIGKILL);
+            libc::waitpid(pgid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+// Exits once last_polled() is more than timeout in the past. Must be called
+// from within the tokio runtime.
+pub fn exit_when_idle<F>(timeout: Duration, last_polled: F)
+where
+    F: Fn() -> Instant + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            let idle = last_polled().elapsed();
+            if idle >= timeout {
+                crate::info!("not polled for {}s, shutting down", idle.as_secs());
+                exit_from_task(0).await
+            }
+
+            tokio::time::sleep(timeout - idle).await;
+        }
+    });
+}
+
+pub fn pid_alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+
+    // it's there, it just isn't ours
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Exits once process pid does, watching it through a pidfd where the kernel
+// supports them and polling for it otherwise.
+pub fn exit_with(pid: u32) {
+    std::thread::spawn(move || {
+        if let Err(err) = wait_for_pidfd(pid) {
+            crate::warn!(
+                "unable to watch pid {} with a pidfd ({}), polling",
+                pid,
+                err
+            );
+            while pid_alive(pid) {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+
+        crate::info!("pid {} exited, shutting down", pid);
+        exit(0)
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn wait_for_pidfd(pid: u32) -> io::Result<()> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) } as libc::c_int;
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // a pidfd becomes readable when its process exits
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let result = loop {
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } >= 0 {
+            break Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            break Err(err);
+        }
+    };
+
+    unsafe { libc::close(fd) };
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_for_pidfd(_pid: u32) -> io::Result<()> {
+    Err(io::Error::other("pidfds are only available on linux"))
+}
+
+// A tcp listener for a genie, bound early so that a bad address or missing
+// token stops the genie before it has left anything in its directory.
+pub struct TcpFront {
+    listener: TcpListener,
+    token: String,
+}
+
+impl TcpFront {
+    // Listens on addr, a port alone meaning localhost. Clients will have to
+    // open with the shared GENIE_TCP_TOKEN.
+    pub fn bind(addr: &str) -> io::Result<TcpFront> {
+        let token = crate::tcp::token()?;
+        let listener = TcpListener::bind(crate::tcp::listen_addr(addr))?;
+        Ok(TcpFront { listener, token })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Passes each client that presents the token through to the genie's own
+    // socket at socket_path.
+    pub fn serve(self, socket_path: PathBuf) {
+        let TcpFront { listener, token } = self;
+        std::thread::spawn(move || serve_tcp(listener, token, socket_path));
+    }
+}
+
+fn serve_tcp(listener: TcpListener, token: String, socket_path: PathBuf) {
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                crate::warn!("error accepting tcp client: {}", err);
+                continue;
+            }
+        };
+
+        let token = token.clone();
+        let socket_path = socket_path.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = pass_through(client, &token, &socket_path) {
+                crate::warn!("tcp client: {}", err);
+            }
+        });
+    }
+}
+
+// how long a tcp client, or the genie answering it, may go without sending
+// anything before the connection is dropped, so neither ties up a thread
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn pass_through(mut client: TcpStream, token: &str, socket_path: &Path) -> io::Result<()> {
+    let peer = client.peer_addr()?;
+
+    // the first line must be "token <token>"
+    client.set_read_timeout(Some(TCP_TIMEOUT))?;
+    let mut reader = io::BufReader::new(client.try_clone()?);
+    let mut line = String::new();
+    reader.by_ref().take(1024).read_line(&mut line)?;
+    let offered = line.trim_end().strip_prefix("token ").unwrap_or("");
+    if !crate::auth::same(offered, token) {
+        client.write_all(crate::auth::REFUSED)?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("refusing {}: wrong token", peer),
+        ));
+    }
+    crate::debug!("tcp client {}", peer);
+
+    // the reader still holds whatever followed the token line
+    let socket_path = SOCKET_PATH
+        .lock()
+        .unwrap()
+        .as_deref()
+        .and_then(current_entry)
+        .unwrap_or_else(|| socket_path.to_path_buf());
+    let mut genie = crate::connect(&socket_path)?;
+    genie.set_read_timeout(Some(TCP_TIMEOUT))?;
+    let mut to_genie = genie.try_clone()?;
+    std::thread::spawn(move || {
+        let _ = io::copy(&mut reader, &mut to_genie);
+        let _ = to_genie.shutdown(Shutdown::Write);
+    });
+
+    io::copy(&mut genie, &mut client)?;
+    client.shutdown(Shutdown::Write)
+}
+
+// Writes response to a client, which may well have hung up by now, as a
+// shell does when its prompt is interrupted. That's no reason to go down.
+pub async fn reply<W: AsyncWrite + Unpin>(stream: &mut W, response: &[u8]) {
+    crate::trace!("responding with {} bytes", response.len());
+    if response.is_empty() {
+        return;
+    }
+    if let Err(err) = stream.write_all(response).await {
+        crate::debug!("client went away before its reply: {}", err);
+    }
+}
+
+// Answers what's left of a request that ended, or went wrong, before it could
+// be parsed: a privileged request without a usable credential is refused, so
+// the client can tell that from a crash, and anything else gets nothing.
+pub async fn reply_unparsed<W: AsyncWrite + Unpin>(stream: &mut W, pending: &[u8]) {
+    if crate::auth::PRIVILEGED
+        .iter()
+        .any(|verb| pending.starts_with(verb))
+    {
+        crate::warn!("refusing privileged request without a credential");
+        reply(stream, crate::auth::REFUSED).await
+    }
+}
+
+// Removes path on the way out, along with whatever the children left in it
+// if it's a directory.
+pub fn remove_on_exit(path: PathBuf) {
+    if let Some(mut paths) = crate::log::lock(&PATHS) {
+        paths.push(path);
+    }
+}
+
+fn remove_paths() {
+    let paths = crate::log::lock(&PATHS)
+        .map(|mut paths| std::mem::take(&mut *paths))
+        .unwrap_or_default();
+
+    for path in paths {
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            // gone with our socket, or moved along with it
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => crate::warn!("unable to remove {}: {}", path.display(), err),
+            Ok(()) => (),
+        }
+    }
+}
+
+pub fn exit(code: i32) -> ! {
+    // whoever got here first is already cleaning up, and will exit for us
+    if EXITING.swap(true, Ordering::SeqCst) {
+        loop {
+            std::thread::park();
+        }
+    }
+
+    cleanup();
+    terminate_children();
+    remove_paths();
+    if code == 0 {
+        crate::log::discard_default();
+    }
+    std::process::exit(code)
+}
+
+// exit() for a task, which mustn't hold up a runtime worker for as long as
+// children take to go.
+pub async fn exit_from_task(code: i32) {
+    let _ = tokio::task::spawn_blocking(move || exit(code)).await;
+    std::future::pending().await
+}
+
+// What every genie is told on its command line, besides what it's to run.
+pub struct Options {
+    pub genie_path: String,
+    pub genie_dir: String,
+    pub name: String,
+    pub logfile: Option<String>,
+    pub log_level: crate::log::Level,
+    pub abstract_ns: bool,
+    pub group: Option<u32>,
+    pub tcp: Option<String>,
+    pub redactor: crate::redact::Redactor,
+    pub foreground: bool,
+    pub label: Option<String>,
+    pub singleton: bool,
+    pub replace: bool,
+    pub idle_timeout: Option<u64>, // milliseconds
+    pub bind_to_pid: Option<u32>,
+}
+
+// a timeout of at least a millisecond, so we don't exit as soon as we start
+fn minutes(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(minutes) if minutes.is_finite() && 60_000.0 * minutes >= 1.0 => Ok(()),
+        _ => Err(format!("{} is not a positive number of minutes", value)),
+    }
+}
+
+fn pid(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(pid) if pid > 0 => Ok(()),
+        _ => Err(format!("{} is not a pid", value)),
+    }
+}
+
+impl Options {
+    // Adds the arguments every genie takes to app.
+    pub fn args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app.arg(Arg::with_name("abstract").long("abstract"))
+            .arg(
+                Arg::with_name("group")
+                    .long("group")
+                    .takes_value(true)
+                    .value_name("group"),
+            )
+            .arg(
+                Arg::with_name("tcp")
+                    .long("tcp")
+                    .takes_value(true)
+                    .value_name("[host:]port"),
+            )
+            .arg(
+                Arg::with_name("redact")
+                    .long("redact")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("regex"),
+            )
+            .arg(Arg::with_name("foreground").long("foreground"))
+            .arg(Arg::with_name("singleton").long("singleton"))
+            .arg(Arg::with_name("replace").long("replace"))
+            .arg(
+                Arg::with_name("logfile")
+                    .short("l")
+                    .long("logfile")
+                    .takes_value(true)
+                    .value_name("file"),
+            )
+            .arg(
+                Arg::with_name("log-level")
+                    .long("log-level")
+                    .takes_value(true)
+                    .value_name("level")
+                    .possible_values(&crate::log::LEVELS),
+            )
+    }
+
+    // Adds those a genie that watches one thing takes besides, as genied,
+    // which runs others, doesn't.
+    pub fn watcher_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app.arg(
+            Arg::with_name("label")
+                .long("label")
+                .takes_value(true)
+                .value_name("label"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .value_name("minutes")
+                .validator(minutes),
+        )
+        .arg(
+            Arg::with_name("bind-to-pid")
+                .long("bind-to-pid")
+                .takes_value(true)
+                .value_name("pid")
+                .validator(pid),
+        )
+    }
+
+    // Reads the options of the genie called name from matches, exiting with
+    // a message if any can't be used.
+    pub fn from_matches(name: &str, matches: &ArgMatches) -> Options {
+        let genie_path = std::env::var("GENIE_PATH").expect("GENIE_PATH env var is not set");
+        let genie_dir = genie_path.split(':').next().unwrap().to_string();
+
+        let log_level = match matches.value_of("log-level") {
+            Some(level) => level.parse().unwrap(),
+            None => crate::log::Level::from_env(),
+        };
+
+        let group = matches.value_of("group").map(|group| {
+            crate::perms::group_id(group).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1)
+            })
+        });
+
+        let redact = matches.values_of("redact").unwrap_or_default();
+        let redactor =
+            crate::redact::Redactor::new(&redact.collect::<Vec<_>>()).unwrap_or_else(|err| {
+                eprintln!("invalid --redact pattern: {}", err);
+                std::process::exit(1)
+            });
+
+        let replace = matches.is_present("replace");
+
+        // both checked by their validators
+        let idle_timeout = matches.value_of("idle-timeout").map(|minutes| {
+            let minutes: f64 = minutes.parse().unwrap();
+            (60_000.0 * minutes) as u64
+        });
+        let bind_to_pid = matches
+            .value_of("bind-to-pid")
+            .map(|pid| pid.parse().unwrap());
+
+        Options {
+            genie_path,
+            genie_dir,
+            name: name.to_string(),
+            logfile: matches.value_of("logfile").map(String::from),
+            log_level,
+            abstract_ns: matches.is_present("abstract"),
+            group,
+            tcp: matches.value_of("tcp").map(String::from),
+            redactor,
+            foreground: matches.is_present("foreground"),
+            label: matches.value_of("label").map(String::from),
+            singleton: replace || matches.is_present("singleton"),
+            replace,
+            idle_timeout,
+            bind_to_pid,
+        }
+    }
+}
+
+// A genie that has bound its socket, in the background unless told to stay
+// in the foreground, but has yet to say it's ready.
+pub struct Started {
+    pub socket_path: PathBuf,
+    listener: std::os::unix::net::UnixListener,
+    auth: Auth,
+    owns_socket: bool,
+    readiness: Readiness,
+    lock: Option<Lock>,
+}
+
+fn refuse_to_start(err: impl Display) -> ! {
+    eprintln!("{}", err);
+    std::process::exit(1)
+}
+
+// Starts the genie options describe, up to the point of serving clients:
+// opens its log, checks its directory, takes its singleton lock, detaches
+// unless it's to stay in the foreground or was handed a socket, binds (or
+// takes the handed socket), writes its token and listens on tcp if asked
+// to. Exits with a message if any of that fails. Call before starting the
+// tokio runtime, while the process is still single threaded.
+pub fn start(options: &Options) -> Started {
+    let Options {
+        genie_path,
+        genie_dir,
+        name,
+        logfile,
+        log_level,
+        abstract_ns,
+        group,
+        tcp,
+        foreground,
+        label,
+        singleton,
+        replace,
+        bind_to_pid,
+        ..
+    } = options;
+
+    if let Err(err) = crate::log::init(name, *log_level, logfile.as_deref().map(Path::new)) {
+        refuse_to_start(format!("unable to open logfile: {}", err))
+    }
+
+    let checked = match group {
+        Some(gid) => crate::perms::check_shared_dir(Path::new(genie_dir), *gid),
+        None => crate::perms::check_owner(Path::new(genie_dir)),
+    };
+    if let Err(err) = checked {
+        refuse_to_start(err)
+    }
+
+    if let Some(pid) = bind_to_pid {
+        if !pid_alive(*pid) {
+            refuse_to_start(format!("cannot bind to pid {}: no such process", pid))
+        }
+    }
+
+    let mut lock = if *singleton {
+        match crate::singleton::acquire(genie_path, genie_dir, name, label.as_deref(), *replace) {
+            Ok(lock) => Some(lock),
+            Err(err) => refuse_to_start(err),
+        }
+    } else {
+        None
+    };
+
+    let tcp_front = tcp.as_ref().map(|tcp| {
+        TcpFront::bind(tcp)
+            .unwrap_or_else(|err| refuse_to_start(format!("unable to listen on {}: {}", tcp, err)))
+    });
+
+    let activated = crate::activated()
+        .unwrap_or_else(|err| refuse_to_start(format!("unable to use passed socket: {}", err)));
+
+    // whoever passed us a socket is supervising us
+    let detached = !foreground && activated.is_none();
+    let readiness = if !detached {
+        Readiness::foreground()
+    } else {
+        let readiness = crate::daemon::detach().expect("failed to fork");
+
+        let daemonize = daemonize::Daemonize::new().working_directory(".");
+        if let Err(err) = daemonize.start() {
+            readiness.fail(format!("failed to daemonize: {}", err))
+        }
+
+        readiness
+    };
+
+    let owns_socket = activated.is_none();
+    let (listener, socket_path) = match activated {
+        Some(activated) => activated,
+        None => match crate::bind(genie_dir, name, *abstract_ns, *group) {
+            Ok(bound) => bound,
+            Err(err) => readiness.fail(format!("failed to bind socket: {}", err)),
+        },
+    };
+    if let Some(lock) = &mut lock {
+        if let Err(err) = lock.record(&socket_path) {
+            readiness.fail(format!("failed to record lock: {}", err))
+        }
+    }
+
+    // in the foreground, whoever started us has our stderr
+    if detached {
+        if let Err(err) = crate::log::log_beside(&socket_path) {
+            readiness.fail(format!("failed to open log: {}", err))
+        }
+    }
+    crate::info!("listening at {}", socket_path.display());
+
+    let auth = match Auth::create(&socket_path) {
+        Ok(auth) => auth,
+        Err(err) => readiness.fail(format!("failed to write token: {}", err)),
+    };
+
+    if let Some(tcp_front) = tcp_front {
+        if let Ok(addr) = tcp_front.local_addr() {
+            crate::info!("listening at {}{}", crate::tcp::SCHEME, addr);
+        }
+        tcp_front.serve(socket_path.clone());
+    }
+
+    if let Err(err) = listener.set_nonblocking(true) {
+        readiness.fail(format!("failed to make socket nonblocking: {}", err))
+    }
+
+    Started {
+        socket_path,
+        listener,
+        auth,
+        owns_socket,
+        readiness,
+        lock,
+    }
+}
+
+impl Started {
+    // Reports that the genie couldn't start after all, and exits.
+    pub fn fail(self, err: impl Display) -> ! {
+        self.readiness.fail(err)
+    }
+
+    // Arranges for the genie's entry to be cleaned up however it goes down,
+    // and for it to go with the process it's bound to, then says it's
+    // ready. Returns the socket to serve clients on and what authorizes
+    // their privileged requests. Must be called from within the tokio
+    // runtime.
+    pub fn ready(self, options: &Options) -> (std::os::unix::net::UnixListener, Auth) {
+        install(if self.owns_socket {
+            Some(self.socket_path.clone())
+        } else {
+            None
+        });
+        remove_on_exit(crate::auth::token_path(&self.socket_path));
+        self.readiness.ready(&self.socket_path);
+
+        if let Some(pid) = options.bind_to_pid {
+            exit_with(pid);
+        }
+
+        // held until we exit, when closing it lets the lock go
+        std::mem::forget(self.lock);
+        (self.listener, self.auth)
+    }
+}
+
+// how long a client gets to send its request, so that one connecting and
+// saying nothing holds up nobody but itself
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// what a genie's parser makes of what a client has sent so far
+pub enum Parsed<R> {
+    Request(R),
+    Incomplete,
+    Malformed,
+}
+
+// what a genie's handler wants done once it has answered a request
+pub enum Then {
+    Continue,
+    // having moved, listen on this socket from now on
+    Listen(std::os::unix::net::UnixListener),
+    Exit,
+}
+
+// Reads a request from stream, answering one that's malformed or cut short
+// as reply_unparsed does.
+async fn read_request<R, P>(stream: &mut UnixStream, parse: &P) -> Option<R>
+where
+    P: Fn(&[u8]) -> Parsed<R>,
+{
+    let mut nbytes = 0;
+    let mut buffer: [u8; 8192] = [0; 8192];
+    loop {
+        match stream.read(&mut buffer[nbytes..]).await {
+            Ok(0) => {
+                reply_unparsed(stream, &buffer[..nbytes]).await;
+                return None;
+            }
+            Ok(length) => nbytes += length,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+
+        match parse(&buffer[..nbytes]) {
+            Parsed::Request(request) => return Some(request),
+            Parsed::Incomplete => continue,
+            Parsed::Malformed => {
+                crate::warn!("malformed request");
+                reply_unparsed(stream, &buffer[..nbytes]).await;
+                return None;
+            }
+        }
+    }
+}
+
+// Accepts clients on listener, each in a task of its own, so that none can
+// hold up another: a client that may talk to us (see check_client) has
+// REQUEST_TIMEOUT to send a request, which parse reads and handle answers,
+// given the client's uid and the stream to answer on. Returns once a
+// handler says to exit. Must be called from within the tokio runtime.
+pub async fn serve<R, P, H, F>(
+    listener: std::os::unix::net::UnixListener,
+    group: Option<u32>,
+    parse: P,
+    handle: H,
+) where
+    R: Debug + Send + 'static,
+    P: Fn(&[u8]) -> Parsed<R> + Send + Sync + 'static,
+    H: Fn(R, u32, UnixStream) -> F + Send + Sync + 'static,
+    F: Future<Output = Then> + Send + 'static,
+{
+    let parse = Arc::new(parse);
+    let handle = Arc::new(handle);
+    let (then, mut thens) = mpsc::channel(1);
+    let mut listener = UnixListener::from_std(listener).expect("failed to listen");
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    crate::warn!("error accepting client: {}", err);
+                    continue;
+                }
+            },
+            Some(next) = thens.recv() => match next {
+                Then::Listen(rebound) => {
+                    listener = UnixListener::from_std(rebound).expect("failed to listen");
+                    continue;
+                }
+                Then::Exit => return,
+                Then::Continue => continue,
+            },
+        };
+
+        let uid = match crate::perms::check_client(stream.as_raw_fd(), group) {
+            Ok(uid) => uid,
+            Err(err) => {
+                crate::warn!("{}", err);
+                continue;
+            }
+        };
+
+        let (parse, handle, then) = (parse.clone(), handle.clone(), then.clone());
+        tokio::spawn(async move {
+            let mut stream = stream;
+            let read = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream, &*parse));
+            let request = match read.await {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(_) => {
+                    crate::warn!("no request within {:?}, hanging up", REQUEST_TIMEOUT);
+                    return;
+                }
+            };
+
+            crate::debug!("{:?} request", request);
+            match handle(request, uid, stream).await {
+                Then::Continue => (),
+                next => {
+                    let _ = then.send(next).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a client sharing our group mustn't be able to hang our prompt by
+    // connecting and saying nothing
+    #[tokio::test]
+    async fn an_idle_client_holds_up_nobody_else() {
+        let path = std::env::temp_dir().join(format!("genie-serve.{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        tokio::spawn(serve(
+            listener,
+            None,
+            |i: &[u8]| match i {
+                b"ping\n" => Parsed::Request(()),
+                _ => Parsed::Incomplete,
+            },
+            |(), _, mut stream| async move {
+                reply(&mut stream, b"pong\n").await;
+                Then::Continue
+            },
+        ));
+
+        let _idle = UnixStream::connect(&path).await.unwrap();
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"ping\n").await.unwrap();
+        let mut response = Vec::new();
+        let read = client.read_to_end(&mut response);
+        let read = tokio::time::timeout(Duration::from_secs(1), read).await;
+        std::fs::remove_file(&path).unwrap();
+        read.expect("held up by the idle client").unwrap();
+        assert_eq!(response, b"pong\n");
+    }
+}
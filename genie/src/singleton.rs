@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::server::KILL_TIMEOUT;
+
+// Held for the life of a singleton genie. The lock file lives in the genie
+// directory, is named for the genie and a hash of its label and working
+// directory, records the pid and socket of whoever holds it, and is removed
+// by its holder on the way out.
+pub struct Lock {
+    file: File,
+}
+
+pub fn lockname(genie_dir: &str, name: &str, label: Option<&str>) -> io::Result<PathBuf> {
+    let mut key = name.as_bytes().to_vec();
+    key.push(0);
+    if let Some(label) = label {
+        key.extend_from_slice(label.as_bytes());
+    }
+    key.push(0);
+    key.extend_from_slice(std::env::current_dir()?.as_os_str().as_bytes());
+
+    Ok(PathBuf::from(format!(
+        "{}/{}.{:016x}.lock",
+        genie_dir,
+        name,
+        crate::cookie::fnv1a(&key)
+    )))
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+}
+
+// Whether file is still the one at path: a holder removes its lock file on
+// the way out, and whoever was waiting on it then holds a lock nobody else
+// can see.
+fn still_at(file: &File, path: &Path) -> io::Result<bool> {
+    let held = file.metadata()?;
+    match std::fs::metadata(path) {
+        Ok(meta) => Ok(meta.dev() == held.dev() && meta.ino() == held.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// Takes the lock for this name, label and working directory. If another
+// genie holds it, either fails or, with replace, asks that genie to exit
+// (signalling it if it doesn't answer) and waits to take over.
+pub fn acquire(
+    genie_path: &str,
+    genie_dir: &str,
+    name: &str,
+    label: Option<&str>,
+    replace: bool,
+) -> io::Result<Lock> {
+    let path = lockname(genie_dir, name, label)?;
+    let mut file = open(&path)?;
+
+    // once we've asked the holder to go, how long we wait and for whom
+    let mut waiting: Option<(Instant, u32)> = None;
+    loop {
+        if crate::try_lock(&file)? {
+            if still_at(&file, &path)? {
+                crate::server::remove_on_exit(path);
+                return Ok(Lock { file });
+            }
+            file = open(&path)?;
+            continue;
+        }
+
+        if let Some((deadline, pid)) = waiting {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out waiting for {}.{:x} to exit", name, pid),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            continue;
+        }
+
+        let (pid, socket_path) = holder(&path)?;
+        if !replace {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} is already running here as {}.{:x} ({}); pass --replace to take over",
+                    name,
+                    name,
+                    pid,
+                    socket_path.display()
+                ),
+            ));
+        }
+
+        // it may have been promoted since it recorded its socket
+        let socket_path = crate::find(genie_path, name, &Some(format!("{:x}", pid)))
+            .map(|(socket_path, _)| socket_path)
+            .unwrap_or(socket_path);
+        if let Err(err) = crate::auth::exit(&socket_path) {
+            eprintln!(
+                "unable to ask {}.{:x} to exit ({}), sending SIGTERM",
+                name, pid, err
+            );
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+        waiting = Some((Instant::now() + KILL_TIMEOUT + Duration::from_secs(2), pid));
+    }
+}
+
+fn holder(path: &Path) -> io::Result<(u32, PathBuf)> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+
+    match (lines.next().map(str::parse), lines.next()) {
+        (Some(Ok(pid)), Some(socket_path)) => Ok((pid, PathBuf::from(socket_path))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is locked, but doesn't say by whom (is the genie still starting?)",
+                path.display()
+            ),
+        )),
+    }
+}
+
+impl Lock {
+    // Notes the current pid and socket in the lock file, once the genie has
+    // daemonized and bound.
+    pub fn record(&mut self, socket_path: &Path) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        writeln!(
+            self.file,
+            "{}\n{}",
+            std::process::id(),
+            socket_path.display()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_a_lock_for_name_label_and_cwd() {
+        let path = lockname("/g", "watch", Some("tests")).unwrap();
+        let name = path.to_str().unwrap();
+        assert!(name.starts_with("/g/watch."));
+        assert!(name.ends_with(".lock"));
+        assert_eq!(name.len(), "/g/watch.".len() + 16 + ".lock".len());
+
+        assert_eq!(lockname("/g", "watch", Some("tests")).unwrap(), path);
+        assert_ne!(lockname("/g", "watch", Some("lint")).unwrap(), path);
+        assert_ne!(lockname("/g", "watch", None).unwrap(), path);
+        assert_ne!(lockname("/g", "tsc", Some("tests")).unwrap(), path);
+        // the name and label can't run into each other
+        assert_ne!(
+            lockname("/g", "ab", None).unwrap(),
+            lockname("/g", "a", Some("b")).unwrap()
+        );
+    }
+}
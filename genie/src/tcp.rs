@@ -0,0 +1,91 @@
+use std::io::{self, prelude::*};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// the token shared between a genie listening on tcp and its clients
+pub const TOKEN_VAR: &str = "GENIE_TCP_TOKEN";
+
+// GENIE_PATH entries of the form tcp://host:port name a genie listening on
+// tcp rather than a directory
+pub const SCHEME: &str = "tcp://";
+
+// where --tcp listens when only given a port
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+pub fn is_tcp(entry: &str) -> bool {
+    entry.starts_with(SCHEME)
+}
+
+pub fn token() -> io::Result<String> {
+    match std::env::var(TOKEN_VAR) {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not set", TOKEN_VAR),
+        )),
+    }
+}
+
+// host:port to listen on for --tcp, which may be just a port
+pub fn listen_addr(spec: &str) -> String {
+    if spec.parse::<u16>().is_ok() {
+        format!("{}:{}", DEFAULT_HOST, spec)
+    } else {
+        spec.to_string()
+    }
+}
+
+// Connects to the genie at entry (tcp://host:port) and authenticates, ready
+// for a request.
+pub fn connect(entry: &str) -> io::Result<TcpStream> {
+    connect_within(entry, None)
+}
+
+fn connect_within(entry: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let addr = entry.strip_prefix(SCHEME).unwrap_or(entry);
+    let mut stream = match timeout {
+        None => TcpStream::connect(addr)?,
+        Some(timeout) => {
+            let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", entry))
+            })?;
+            let stream = TcpStream::connect_timeout(&addr, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            stream
+        }
+    };
+    stream.write_all(format!("token {}\n", token()?).as_bytes())?;
+    Ok(stream)
+}
+
+// Sends a single request to the genie at entry, returning its full response.
+pub fn request(entry: &str, request: &str) -> io::Result<Vec<u8>> {
+    send(connect(entry)?, entry, request)
+}
+
+// Like request, but gives up on a genie that can't be reached, or doesn't
+// answer, within timeout.
+pub fn request_within(entry: &str, request: &str, timeout: Duration) -> io::Result<Vec<u8>> {
+    send(connect_within(entry, Some(timeout))?, entry, request)
+}
+
+// Like crate::peek, for the genie at entry.
+pub fn peek(entry: &str, cookie: &str) -> io::Result<Option<bool>> {
+    let response = request_within(entry, &format!("peek\n{}\n", cookie), crate::QUERY_TIMEOUT)?;
+    Ok(crate::unseen(&response))
+}
+
+fn send(mut stream: TcpStream, entry: &str, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    if response == crate::auth::REFUSED {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} refused our {}", entry, TOKEN_VAR),
+        ));
+    }
+    Ok(response)
+}
@@ -0,0 +1,675 @@
+use std::{
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::UnixStream,
+    process::{Child, Command},
+    sync::{mpsc, oneshot},
+};
+
+use conf::{configure, Config};
+use genie::auth::{Auth, Credential};
+use genie::server::{Parsed, Then};
+
+#[derive(Debug)]
+pub enum Request {
+    Poll(GenieCookie),
+    Get(GenieCookie),
+    Peek(GenieCookie),
+    Latest,
+    Forget(GenieCookie),
+    MultiPoll(GenieCookie),
+    Info,
+    Exit(Credential),
+    // the pid, in hex, of the genie to restart
+    Restart(Credential, String),
+}
+
+mod parse {
+    use super::{Credential, GenieCookie, Parsed, Request};
+    use nom::{
+        branch::alt,
+        bytes::streaming::tag,
+        character::streaming::{alphanumeric1, newline},
+        IResult,
+    };
+
+    fn genie_cookie(i: &[u8]) -> IResult<&[u8], GenieCookie> {
+        let (i, cookie) = alphanumeric1(i)?;
+        match std::str::from_utf8(cookie) {
+            Ok(cookie) => Ok((i, GenieCookie(cookie.to_string()))),
+            Err(_) => panic!(
+                "we were already told it was alphanumeric, how does it have incomplete utf8?"
+            ),
+        }
+    }
+
+    fn poll_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("poll\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Poll(cookie)))
+    }
+
+    fn get_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("get\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Get(cookie)))
+    }
+
+    fn peek_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("peek\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Peek(cookie)))
+    }
+
+    fn forget_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("forget\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Forget(cookie)))
+    }
+
+    fn latest_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("latest\n")(i)?;
+        Ok((i, Request::Latest))
+    }
+
+    fn multipoll_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("multipoll\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::MultiPoll(cookie)))
+    }
+
+    fn info_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("info\n")(i)?;
+        Ok((i, Request::Info))
+    }
+
+    fn exit_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("exit\n")(i)?;
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Exit(Credential(credential))))
+    }
+
+    fn restart_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("restart\n")(i)?;
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        let (i, pid) = alphanumeric1(i)?;
+        let (i, _) = newline(i)?;
+        let pid = String::from_utf8_lossy(pid).into_owned();
+        Ok((i, Request::Restart(Credential(credential), pid)))
+    }
+
+    fn request(i: &[u8]) -> IResult<&[u8], Request> {
+        alt((
+            poll_request,
+            get_request,
+            peek_request,
+            latest_request,
+            forget_request,
+            multipoll_request,
+            info_request,
+            exit_request,
+            restart_request,
+        ))(i)
+    }
+
+    pub fn parsed(i: &[u8]) -> Parsed<Request> {
+        match request(i) {
+            Ok((_, request)) => Parsed::Request(request),
+            Err(nom::Err::Incomplete(_)) => Parsed::Incomplete,
+            Err(_) => Parsed::Malformed,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Debug)]
+pub struct GenieCookie(String);
+
+impl GenieCookie {
+    fn of(self, uid: u32) -> GenieCookie {
+        GenieCookie(genie::cookie::of_user(uid, &self.0))
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Where a supervised genie is told to restart: it's sent on once the genie
+// started in its place is listening, or why none is.
+type Restarted = oneshot::Sender<Result<PathBuf, String>>;
+
+struct Supervised {
+    name: String,
+    pid: String,
+    socket_path: PathBuf,
+    restart: mpsc::Sender<Restarted>,
+}
+
+struct SupervisorState {
+    // one slot per genie on the command line, filled while it's listening
+    genies: Vec<Option<Supervised>>,
+}
+
+impl SupervisorState {
+    fn new(count: usize) -> SupervisorState {
+        SupervisorState {
+            genies: (0..count).map(|_| None).collect(),
+        }
+    }
+}
+
+// what stands in for the response of a genie that doesn't answer in time
+const UNAVAILABLE: &[u8] = b"(not answering)\n";
+
+// Sends request to every genie currently listening, returning the name, pid
+// and (redacted) response of each that answered, or UNAVAILABLE for one that
+// took longer than QUERY_TIMEOUT: a hung genie mustn't hold up every prompt.
+async fn ask_all(
+    state: &Arc<Mutex<SupervisorState>>,
+    redactor: &genie::redact::Redactor,
+    request: String,
+) -> Vec<(String, String, Vec<u8>)> {
+    let asks = state
+        .lock()
+        .unwrap()
+        .genies
+        .iter()
+        .flatten()
+        .map(|genie| {
+            let socket_path = genie.socket_path.clone();
+            let request = request.clone();
+            (
+                genie.name.clone(),
+                genie.pid.clone(),
+                tokio::task::spawn_blocking(move || {
+                    genie::request_within(&socket_path, &request, genie::QUERY_TIMEOUT)
+                }),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut responses = Vec::new();
+    for (name, pid, ask) in asks {
+        match ask.await.unwrap() {
+            Ok(response) => responses.push((name, pid, redactor.apply(&response))),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                genie::warn!("{}.{}: not answering", name, pid);
+                responses.push((name, pid, UNAVAILABLE.to_vec()));
+            }
+            Err(err) => genie::warn!("{}.{}: error sending request: {}", name, pid, err),
+        }
+    }
+
+    responses
+}
+
+// for clients that only speak the per-genie protocol
+fn prefix_lines(responses: Vec<(String, String, Vec<u8>)>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, pid, response) in responses {
+        for line in String::from_utf8_lossy(&response).lines() {
+            out.extend_from_slice(format!("{}({}): {}\n", name, pid, line).as_bytes());
+        }
+    }
+    out
+}
+
+// Asks a genie to exit, as genie kill would, killing it if it won't, and
+// waits for it.
+async fn stop(child: &mut Child, socket_path: Option<PathBuf>) -> std::io::Result<ExitStatus> {
+    if let Some(socket_path) = socket_path {
+        let asked = tokio::task::spawn_blocking(move || genie::auth::exit(&socket_path))
+            .await
+            .unwrap();
+        match asked {
+            Ok(()) => {
+                if let Ok(status) =
+                    tokio::time::timeout(genie::auth::EXIT_TIMEOUT, child.wait()).await
+                {
+                    return status;
+                }
+            }
+            Err(err) => genie::warn!("unable to ask it to exit: {}", err),
+        }
+    }
+
+    child.kill().await?;
+    child.wait().await
+}
+
+async fn supervise(
+    index: usize,
+    argv: Vec<String>,
+    genie_dir: PathBuf,
+    log_level: genie::log::Level,
+    state: Arc<Mutex<SupervisorState>>,
+) {
+    let cmdline = argv.join(" ");
+    let re = regex::Regex::new(genie::SOCKNAME_PATTERN).unwrap();
+    let mut backoff = MIN_BACKOFF;
+    let (restart, mut restarts) = mpsc::channel(1);
+    // whoever asked for the genie to be restarted, until it's started again
+    let mut restarting: Option<Restarted> = None;
+    let answer = |restarting: &mut Option<Restarted>, result| {
+        if let Some(restarted) = restarting.take() {
+            let _ = restarted.send(result);
+        }
+    };
+
+    loop {
+        let started = Instant::now();
+        let mut child = match genie::server::spawn_group(
+            Command::new(&argv[0])
+                .arg("--foreground")
+                .args(&argv[1..])
+                .env("GENIE_PATH", &genie_dir)
+                .env(genie::log::LEVEL_VAR, log_level.to_string())
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        ) {
+            Ok(child) => child,
+            Err(err) => {
+                genie::error!("unable to start {}: {}", cmdline, err);
+                answer(
+                    &mut restarting,
+                    Err(format!("unable to start it again: {}", err)),
+                );
+                return;
+            }
+        };
+        let pid = child.id();
+
+        // in the foreground, a genie reports its socket path once it's bound
+        let stdout = child
+            .stdout
+            .take()
+            .expect("stdout from child process was unavailable");
+        let mut stdout = BufReader::new(stdout).lines();
+        match stdout.next_line().await {
+            Ok(Some(socket_path)) => {
+                let socket_path = PathBuf::from(socket_path);
+                let filename = socket_path
+                    .file_name()
+                    .and_then(|filename| filename.to_str())
+                    .unwrap_or_default();
+                match re.captures(filename) {
+                    Some(captures) => {
+                        genie::info!("{} is listening at {}", cmdline, socket_path.display());
+                        answer(&mut restarting, Ok(socket_path.clone()));
+                        state.lock().unwrap().genies[index] = Some(Supervised {
+                            name: captures[1].to_string(),
+                            pid: captures[2].to_string(),
+                            socket_path,
+                            restart: restart.clone(),
+                        });
+                    }
+                    None => genie::error!(
+                        "{} reported an unexpected socket: {}",
+                        cmdline,
+                        socket_path.display()
+                    ),
+                }
+            }
+            Ok(None) => genie::error!("{} exited before binding its socket", cmdline),
+            Err(err) => genie::error!("error reading from {}: {}", cmdline, err),
+        }
+        answer(&mut restarting, Err("it did not start again".to_string()));
+
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = stdout.next_line().await {
+                genie::info!("{}", line);
+            }
+        });
+
+        // what it logs, it logs to its stderr, already timestamped
+        let stderr = child
+            .stderr
+            .take()
+            .expect("stderr from child process was unavailable");
+        let mut stderr = BufReader::new(stderr).lines();
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = stderr.next_line().await {
+                genie::log::relay(&line);
+            }
+        });
+
+        // until it exits, or someone asks for it to be restarted
+        let status = tokio::select! {
+            status = child.wait() => status,
+            Some(restarted) = restarts.recv() => {
+                genie::info!("restarting {} on request", cmdline);
+                restarting = Some(restarted);
+                let socket_path = state.lock().unwrap().genies[index]
+                    .as_ref()
+                    .map(|genie| genie.socket_path.clone());
+                stop(&mut child, socket_path).await
+            }
+        };
+        genie::server::forget_group(pid);
+
+        // a genie that was killed can't have cleaned up after itself
+        if let Some(genie) = state.lock().unwrap().genies[index].take() {
+            let _ = std::fs::remove_file(&genie.socket_path);
+        }
+
+        match status {
+            // started again straight away, however it went
+            _ if restarting.is_some() => continue,
+            Ok(status) if status.success() => {
+                genie::info!("{} exited", cmdline);
+                return;
+            }
+            Ok(status) => genie::warn!("{} died ({})", cmdline, status),
+            Err(err) => genie::error!("error waiting for {}: {}", cmdline, err),
+        }
+
+        if started.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+
+        genie::info!("restarting {} in {}s", cmdline, backoff.as_secs());
+        tokio::time::sleep(backoff).await;
+        backoff = std::cmp::min(2 * backoff, MAX_BACKOFF);
+    }
+}
+
+mod conf {
+    use clap::{App, Arg};
+    use genie::server::Options;
+
+    pub struct Config {
+        pub options: Options,
+        pub genies: Vec<Vec<String>>,
+    }
+
+    // Splits a genie command line into its argv the way a shell would, minus
+    // expansions: words are separated by whitespace, which quotes and
+    // backslashes keep within a word.
+    pub fn split_command(line: &str) -> Result<Vec<String>, String> {
+        let mut argv = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => argv.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated '".to_string()),
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            // only these are escaped within double quotes
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                }
+                                None => return Err("unterminated \"".to_string()),
+                            },
+                            Some(c) => word.push(c),
+                            None => return Err("unterminated \"".to_string()),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(c) => word.get_or_insert_with(String::new).push(c),
+                    None => return Err("trailing \\".to_string()),
+                },
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        argv.extend(word);
+        Ok(argv)
+    }
+
+    pub fn configure() -> Config {
+        let app = App::new("genied")
+            .author("David L. L. Thomas <davidleothomas@gmail.com>")
+            .about("run and supervise genies, answering polls for all of them");
+        let matches = Options::args(app)
+            .arg(Arg::from_usage(
+                "<genie>... 'genie command lines to run, quoted as for a shell, e.g. \"watchg -n 30 'make test'\"'",
+            ))
+            .get_matches();
+
+        let options = Options::from_matches(genie::SUPERVISOR_NAME, &matches);
+
+        let genies = matches
+            .values_of("genie")
+            .unwrap()
+            .map(|genie| {
+                split_command(genie).unwrap_or_else(|err| {
+                    eprintln!("invalid genie command line {:?}: {}", genie, err);
+                    std::process::exit(1)
+                })
+            })
+            .filter(|argv: &Vec<String>| !argv.is_empty())
+            .collect();
+
+        Config { options, genies }
+    }
+}
+
+// what every request is answered from
+struct Genied {
+    state: Arc<Mutex<SupervisorState>>,
+    redactor: genie::redact::Redactor,
+    auth: Auth,
+    info: Vec<u8>,
+}
+
+// Asks the genie with the given pid to restart, returning where it's
+// listening once it has.
+async fn restart(state: &Mutex<SupervisorState>, pid: String) -> Result<PathBuf, String> {
+    let restart = state
+        .lock()
+        .unwrap()
+        .genies
+        .iter()
+        .flatten()
+        .find(|genie| genie.pid == pid)
+        .map(|genie| genie.restart.clone());
+    match restart {
+        Some(restart) => {
+            let (restarted, started) = oneshot::channel();
+            match restart.send(restarted).await {
+                Ok(()) => started
+                    .await
+                    .unwrap_or_else(|_| Err("it exited".to_string())),
+                Err(_) => Err("it exited".to_string()),
+            }
+        }
+        None => Err(format!("no genie {} here", pid)),
+    }
+}
+
+async fn handle(genied: Arc<Genied>, request: Request, uid: u32, mut stream: UnixStream) -> Then {
+    let (state, redactor) = (&genied.state, &genied.redactor);
+    match request {
+        Request::Poll(cookie) => {
+            let GenieCookie(cookie) = cookie.of(uid);
+            let responses = ask_all(state, redactor, format!("poll\n{}\n", cookie)).await;
+            genie::server::reply(&mut stream, &prefix_lines(responses)).await
+        }
+        Request::Get(cookie) => {
+            let GenieCookie(cookie) = cookie.of(uid);
+            let responses = ask_all(state, redactor, format!("get\n{}\n", cookie)).await;
+            genie::server::reply(&mut stream, &prefix_lines(responses)).await
+        }
+        Request::Forget(cookie) => {
+            let GenieCookie(cookie) = cookie.of(uid);
+            ask_all(state, redactor, format!("forget\n{}\n", cookie)).await;
+        }
+        Request::Latest => {
+            let responses = ask_all(state, redactor, "latest\n".to_string()).await;
+            genie::server::reply(&mut stream, &prefix_lines(responses)).await
+        }
+        Request::Peek(cookie) => {
+            let GenieCookie(cookie) = cookie.of(uid);
+            let responses = ask_all(state, redactor, format!("peek\n{}\n", cookie)).await;
+            let unseen = responses
+                .iter()
+                .any(|(_, _, response)| response == genie::UNSEEN);
+            genie::server::reply(
+                &mut stream,
+                if unseen { genie::UNSEEN } else { genie::SEEN },
+            )
+            .await
+        }
+        Request::MultiPoll(cookie) => {
+            let GenieCookie(cookie) = cookie.of(uid);
+            let mut out = Vec::new();
+            for (name, pid, response) in
+                ask_all(state, redactor, format!("poll\n{}\n", cookie)).await
+            {
+                if !response.is_empty() {
+                    genie::write_multi(&mut out, &name, &pid, &response);
+                }
+            }
+            genie::server::reply(&mut stream, &out).await
+        }
+        Request::Info => genie::server::reply(&mut stream, &genied.info).await,
+        Request::Exit(credential) => {
+            if genied.auth.allows(&credential) {
+                return Then::Exit;
+            }
+            genie::warn!("refusing unauthorized exit request");
+            genie::server::reply(&mut stream, genie::auth::REFUSED).await
+        }
+        Request::Restart(credential, pid) => {
+            if !genied.auth.allows(&credential) {
+                genie::warn!("refusing unauthorized restart request");
+                genie::server::reply(&mut stream, genie::auth::REFUSED).await;
+                return Then::Continue;
+            }
+            let response = match restart(state, pid).await {
+                Ok(socket_path) => format!("{}\n", socket_path.display()),
+                Err(err) => format!("{}{}\n", genie::auth::MOVE_FAILED, err),
+            };
+            genie::server::reply(&mut stream, response.as_bytes()).await
+        }
+    }
+    Then::Continue
+}
+
+fn main() {
+    let Config { options, genies } = configure();
+    let state = Arc::new(Mutex::new(SupervisorState::new(genies.len())));
+
+    let started = genie::server::start(&options);
+
+    // the genies we run keep their sockets out of the way of genie_poll,
+    // which asks us instead
+    let supervised_dir = genie::supervised_dir(&options.genie_dir, std::process::id());
+    if let Err(err) = std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&supervised_dir)
+    {
+        started.fail(format!(
+            "failed to create {}: {}",
+            supervised_dir.display(),
+            err
+        ))
+    }
+
+    let info = genie::info::Info::current(&options.name, None).to_bytes();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            genie::server::remove_on_exit(supervised_dir.clone());
+            let (listener, auth) = started.ready(&options);
+
+            for (index, argv) in genies.into_iter().enumerate() {
+                tokio::spawn(supervise(
+                    index,
+                    argv,
+                    supervised_dir.clone(),
+                    options.log_level,
+                    state.clone(),
+                ));
+            }
+
+            let genied = Arc::new(Genied {
+                state: state.clone(),
+                redactor: options.redactor.clone(),
+                auth,
+                info,
+            });
+            genie::server::serve(
+                listener,
+                options.group,
+                parse::parsed,
+                move |request, uid, stream| handle(genied.clone(), request, uid, stream),
+            )
+            .await;
+
+            genie::server::exit(0)
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::conf::split_command;
+
+    fn argv(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            split_command("  watchg -n\t30   make ").unwrap(),
+            argv(&["watchg", "-n", "30", "make"])
+        );
+        assert_eq!(split_command("").unwrap(), argv(&[]));
+    }
+
+    #[test]
+    fn keeps_quoted_words_whole() {
+        assert_eq!(
+            split_command(r#"watchg -n 30 'make test' "echo \"hi\" \n" a\ b"#).unwrap(),
+            argv(&["watchg", "-n", "30", "make test", r#"echo "hi" \n"#, "a b"])
+        );
+        assert_eq!(split_command("x '' \"\"").unwrap(), argv(&["x", "", ""]));
+        assert_eq!(split_command("a'b'\"c\"d").unwrap(), argv(&["abcd"]));
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert!(split_command("watchg 'make").is_err());
+        assert!(split_command("watchg \"make").is_err());
+        assert!(split_command("watchg make\\").is_err());
+    }
+}
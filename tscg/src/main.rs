@@ -1,31 +1,38 @@
 use std::{
     collections::HashMap,
-    fs::remove_file,
     process::Stdio,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
-    net::{UnixListener, UnixStream},
-    prelude::*,
+    net::UnixStream,
     process::{Child, Command},
     stream::StreamExt,
 };
 
 use conf::{configure, Config};
+use genie::auth::{Auth, Credential};
+use genie::server::{Parsed, Then};
 
+#[derive(Debug)]
 pub enum Request {
     Poll(GenieCookie),
     Get(GenieCookie),
-    Exit,
+    Peek(GenieCookie),
+    Latest,
+    Forget(GenieCookie),
+    Info,
+    Exit(Credential),
+    Move(Credential, String),
 }
 
 mod parse {
-    use super::{GenieCookie, Request};
+    use super::{Credential, GenieCookie, Parsed, Request};
     use nom::{
         branch::alt,
-        bytes::streaming::tag,
+        bytes::streaming::{tag, take_till1},
         character::streaming::{alphanumeric1, newline},
         IResult,
     };
@@ -54,22 +61,83 @@ mod parse {
         Ok((i, Request::Get(cookie)))
     }
 
+    fn peek_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("peek\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Peek(cookie)))
+    }
+
+    fn forget_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("forget\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Forget(cookie)))
+    }
+
+    fn latest_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("latest\n")(i)?;
+        Ok((i, Request::Latest))
+    }
+
+    fn info_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("info\n")(i)?;
+        Ok((i, Request::Info))
+    }
+
     fn exit_request(i: &[u8]) -> IResult<&[u8], Request> {
         let (i, _) = tag("exit\n")(i)?;
-        Ok((i, Request::Exit))
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Exit(Credential(credential))))
+    }
+
+    fn move_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("move\n")(i)?;
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        let (i, dir) = take_till1(|c| c == b'\n')(i)?;
+        let (i, _) = newline(i)?;
+        let dir = String::from_utf8_lossy(dir).into_owned();
+        Ok((i, Request::Move(Credential(credential), dir)))
     }
 
-    pub fn request(i: &[u8]) -> IResult<&[u8], Request> {
-        alt((poll_request, get_request, exit_request))(i)
+    fn request(i: &[u8]) -> IResult<&[u8], Request> {
+        alt((
+            poll_request,
+            get_request,
+            peek_request,
+            latest_request,
+            forget_request,
+            info_request,
+            exit_request,
+            move_request,
+        ))(i)
+    }
+
+    pub fn parsed(i: &[u8]) -> Parsed<Request> {
+        match request(i) {
+            Ok((_, request)) => Parsed::Request(request),
+            Err(nom::Err::Incomplete(_)) => Parsed::Incomplete,
+            Err(_) => Parsed::Malformed,
+        }
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 pub struct GenieCookie(String);
 
+impl GenieCookie {
+    // users sharing a genie keep track of what they've seen separately
+    fn of(self, uid: u32) -> GenieCookie {
+        GenieCookie(genie::cookie::of_user(uid, &self.0))
+    }
+}
+
 struct TscState {
-    fingers: HashMap<GenieCookie, (u32, Option<Arc<(u16, String)>>)>,
+    fingers: HashMap<GenieCookie, (u32, Option<Arc<(u16, String)>>, Instant)>,
     latest: Option<(u32, Option<Arc<(u16, String)>>)>,
+    started: Instant,
 }
 
 impl TscState {
@@ -77,6 +145,7 @@ impl TscState {
         return TscState {
             fingers: HashMap::new(),
             latest: None,
+            started: Instant::now(),
         };
     }
 
@@ -88,9 +157,12 @@ impl TscState {
         match &self.latest {
             None => None,
             Some((iteration, output)) => {
-                match &self.fingers.insert(cookie, (*iteration, output.clone())) {
+                match &self
+                    .fingers
+                    .insert(cookie, (*iteration, output.clone(), Instant::now()))
+                {
                     None => Some(output.clone()),
-                    Some((last_polled, _)) => {
+                    Some((last_polled, _, _)) => {
                         if iteration == last_polled {
                             None
                         } else {
@@ -108,64 +180,84 @@ impl TscState {
                 None => None,
                 Some((_, output)) => Some(output.clone()),
             },
-            Some((_, output)) => Some(output.clone()),
+            Some((_, output, _)) => Some(output.clone()),
         }
     }
+
+    // for a client that won't be polling again
+    fn forget(&mut self, cookie: &GenieCookie) {
+        self.fingers.remove(cookie);
+    }
+
+    // what poll would show someone who has never polled
+    fn latest(&self) -> Option<Option<Arc<(u16, String)>>> {
+        self.latest.as_ref().map(|(_, output)| output.clone())
+    }
+
+    // whether poll would have something for cookie
+    fn peek(&self, cookie: &GenieCookie) -> bool {
+        match (&self.latest, self.fingers.get(cookie)) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some((iteration, _)), Some((last_polled, _, _))) => iteration != last_polled,
+        }
+    }
+
+    fn last_polled(&self) -> Instant {
+        self.fingers
+            .values()
+            .map(|(_, _, polled)| *polled)
+            .max()
+            .unwrap_or(self.started)
+    }
 }
 
 async fn send_error_count_to_stream(stream: &mut UnixStream, output: &Option<Arc<(u16, String)>>) {
     match output {
         Some(output) => {
+            genie::trace!("responding with {} errors", output.0);
             let msg = format!("{} errors", output.0);
-            stream.write_all(msg.as_bytes()).await.unwrap()
+            genie::server::reply(stream, msg.as_bytes()).await
         }
         None => {}
     }
 }
 
 async fn send_output_to_stream(stream: &mut UnixStream, output: &Option<Arc<(u16, String)>>) {
+    genie::trace!(
+        "responding with {} bytes",
+        output.as_ref().map_or(0, |output| output.1.len())
+    );
     match output {
         Some(output) => {
             if output.1.len() > 0 {
-                stream.write_all(output.1.as_bytes()).await.unwrap()
+                genie::server::reply(stream, output.1.as_bytes()).await
             } else {
-                stream
-                    .write_all("... no output ...\n".as_bytes())
-                    .await
-                    .unwrap()
+                genie::server::reply(stream, "... no output ...\n".as_bytes()).await
             }
         }
-        None => stream.write_all("compiling...\n".as_bytes()).await.unwrap(),
+        None => genie::server::reply(stream, "compiling...\n".as_bytes()).await,
     }
 }
 
 mod conf {
     use clap::{App, AppSettings, Arg};
+    use genie::server::Options;
 
     pub struct Config {
-        pub genie_dir: String,
-        pub name: String,
+        pub options: Options,
         pub args: Vec<String>,
-        pub logfile: Option<String>,
     }
 
     pub fn configure() -> Config {
-        let matches = App::new("tscg")
+        let app = App::new("tscg")
             .setting(AppSettings::TrailingVarArg)
-            .author("David L. L. Thomas <davidleothomas@gmail.com>")
+            .author("David L. L. Thomas <davidleothomas@gmail.com>");
+        let matches = Options::watcher_args(Options::args(app))
             .arg(Arg::from_usage("[arg]... 'args to pass to tsc'"))
             .get_matches();
 
-        let name = "tsc".to_string();
-        let genie_dir = std::env::var("GENIE_PATH")
-            .ok()
-            .expect("GENIE_PATH env var is not set")
-            .split(":")
-            .next()
-            .unwrap()
-            .to_string();
-
-        let logfile = matches.value_of("logfile").map(String::from);
+        let options = Options::from_matches("tsc", &matches);
 
         let args = matches
             .values_of("arg")
@@ -173,124 +265,136 @@ mod conf {
             .map(ToString::to_string)
             .collect();
 
-        Config {
-            genie_dir,
-            name,
-            args,
-            logfile,
-        }
+        Config { options, args }
     }
 }
 
-fn main() {
-    let Config {
-        genie_dir,
-        name,
-        args,
-        logfile,
-    } = configure();
-    let state = Arc::new(Mutex::new(TscState::new()));
-
-    let daemonize = daemonize::Daemonize::new().working_directory(".");
-
-    let daemonize = match logfile {
-        Some(logfile) => {
-            let logfile = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(logfile)
-                .expect("unable to open logfile");
-
-            let stdout = logfile.try_clone().expect("unable to clone logfile handle");
-            let stderr = logfile;
+// what every request is answered from
+struct Tsc {
+    state: Arc<Mutex<TscState>>,
+    auth: Auth,
+    info: Vec<u8>,
+    name: String,
+    group: Option<u32>,
+}
 
-            daemonize.stdout(stdout).stderr(stderr)
+async fn handle(tsc: Arc<Tsc>, request: Request, uid: u32, mut stream: UnixStream) -> Then {
+    let state = &tsc.state;
+    match request {
+        Request::Poll(cookie) => {
+            let output = state.lock().unwrap().poll(cookie.of(uid)).clone();
+            if let Some(output) = output {
+                send_error_count_to_stream(&mut stream, &output).await
+            }
         }
-        None => daemonize,
-    };
+        Request::Get(cookie) => {
+            let output = state.lock().unwrap().get(cookie.of(uid)).clone();
+            if let Some(output) = output {
+                send_output_to_stream(&mut stream, &output).await
+            }
+        }
+        Request::Forget(cookie) => state.lock().unwrap().forget(&cookie.of(uid)),
+        Request::Latest => {
+            let output = state.lock().unwrap().latest();
+            if let Some(output) = output {
+                send_output_to_stream(&mut stream, &output).await
+            }
+        }
+        Request::Peek(cookie) => {
+            let unseen = state.lock().unwrap().peek(&cookie.of(uid));
+            genie::server::reply(
+                &mut stream,
+                if unseen { genie::UNSEEN } else { genie::SEEN },
+            )
+            .await
+        }
+        Request::Info => genie::server::reply(&mut stream, &tsc.info).await,
+        Request::Move(credential, dir) => {
+            if !tsc.auth.allows(&credential) {
+                genie::warn!("refusing unauthorized move request");
+                genie::server::reply(&mut stream, genie::auth::REFUSED).await;
+                return Then::Continue;
+            }
+            let (response, then) = match genie::server::rebind(&dir, &tsc.name, tsc.group) {
+                Ok((listener, socket_path)) => {
+                    genie::info!("listening at {}", socket_path.display());
+                    (
+                        format!("{}\n", socket_path.display()),
+                        Then::Listen(listener),
+                    )
+                }
+                Err(err) => {
+                    genie::warn!("unable to move to {}: {}", dir, err);
+                    (
+                        format!("{}{}\n", genie::auth::MOVE_FAILED, err),
+                        Then::Continue,
+                    )
+                }
+            };
+            genie::server::reply(&mut stream, response.as_bytes()).await;
+            return then;
+        }
+        Request::Exit(credential) => {
+            if tsc.auth.allows(&credential) {
+                return Then::Exit;
+            }
+            genie::warn!("refusing unauthorized exit request");
+            genie::server::reply(&mut stream, genie::auth::REFUSED).await
+        }
+    }
+    Then::Continue
+}
 
-    daemonize.start().expect("failed to daemonize");
+fn main() {
+    let Config { options, args } = configure();
+    let state = Arc::new(Mutex::new(TscState::new()));
 
-    let socket_path = { format!("{}/{}.{:x}.sock", genie_dir, name, std::process::id()) };
+    let started = genie::server::start(&options);
+    let info = genie::info::Info::current(&options.name, options.label.as_deref()).to_bytes();
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            {
-                let state = state.clone();
-                let mut listener = UnixListener::bind(&socket_path).unwrap();
-                tokio::spawn(async move {
-                    'top: loop {
-                        if let Some(Ok(mut stream)) = listener.next().await {
-                            let mut nbytes = 0;
-                            let mut buffer: [u8; 8192] = [0; 8192];
-                            'stream: loop {
-                                match stream.read(&mut buffer[nbytes..]).await {
-                                    Ok(0) => break,
-                                    Ok(length) => nbytes += length,
-                                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
-                                        continue
-                                    }
-                                    Err(_err) => break 'stream,
-                                }
-
-                                loop {
-                                    match parse::request(&buffer[..nbytes]) {
-                                        Ok((_, request)) => {
-                                            match request {
-                                                Request::Poll(cookie) => {
-                                                    let output =
-                                                        state.lock().unwrap().poll(cookie).clone();
-                                                    if let Some(output) = output {
-                                                        send_error_count_to_stream(
-                                                            &mut stream,
-                                                            &output,
-                                                        )
-                                                        .await
-                                                    }
-                                                }
-                                                Request::Get(cookie) => {
-                                                    let output =
-                                                        state.lock().unwrap().get(cookie).clone();
-                                                    if let Some(output) = output {
-                                                        send_output_to_stream(&mut stream, &output)
-                                                            .await
-                                                    }
-                                                }
-                                                Request::Exit => break 'top,
-                                            }
-                                            break 'stream;
-                                        }
-                                        Err(nom::Err::Incomplete(_)) => continue 'stream,
-                                        Err(_) => break 'stream,
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    match remove_file(socket_path) {
-                        Err(_) => (),
-                        Ok(_) => (),
-                    }
+            let (listener, auth) = started.ready(&options);
 
-                    std::process::exit(0)
+            if let Some(idle_timeout) = options.idle_timeout {
+                let state = state.clone();
+                genie::server::exit_when_idle(Duration::from_millis(idle_timeout), move || {
+                    state.lock().unwrap().last_polled()
                 });
             }
 
+            let tsc = Arc::new(Tsc {
+                state: state.clone(),
+                auth,
+                info,
+                name: options.name.clone(),
+                group: options.group,
+            });
+            tokio::spawn(async move {
+                genie::server::serve(
+                    listener,
+                    tsc.group,
+                    parse::parsed,
+                    move |request, uid, stream| handle(tsc.clone(), request, uid, stream),
+                )
+                .await;
+                genie::server::exit_from_task(0).await
+            });
+
             let mut iteration: u32 = 0;
 
-            let tsc: Child = args
-                .into_iter()
-                .fold(Command::new("tsc").arg("--watch"), |tsc, arg| tsc.arg(arg))
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .kill_on_drop(true)
-                .spawn()
-                .expect("faild to spawn tsc process");
+            let tsc: Child = genie::server::spawn_group(
+                args.into_iter()
+                    .fold(Command::new("tsc").arg("--watch"), |tsc, arg| tsc.arg(arg))
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .kill_on_drop(true),
+            )
+            .expect("faild to spawn tsc process");
 
             let stdout = tsc
                 .stdout
@@ -317,17 +421,18 @@ fn main() {
             let end =
                 regex::Regex::new("Found ([0-9]+) errors?. Watching for file changes.").unwrap();
 
-            loop {
-                match input.next().await.unwrap() {
+            while let Some(line) = input.next().await {
+                match line {
                     Ok(line) => {
                         if start.is_match(&line) {
                             state.lock().unwrap().update(iteration, None)
                         }
 
-                        output.push(line.to_string());
+                        output.push(options.redactor.apply_str(&line));
 
                         if let Some(captures) = end.captures(&line) {
                             let error_count = captures.get(1).unwrap().as_str().parse().unwrap();
+                            genie::debug!("iteration {}: {} errors", iteration, error_count);
 
                             let mut output = output.drain(..).collect::<Vec<String>>().join("\n");
                             output.push('\n');
@@ -339,11 +444,16 @@ fn main() {
                     }
 
                     Err(line) => {
-                        output.push(format!("err: {}\n", line.to_string()));
+                        output.push(format!("err: {}\n", options.redactor.apply_str(&line)));
                     }
                 }
 
                 iteration += 1;
             }
+
+            // as it is once we're shutting down, when this waits for the exit
+            // under way
+            genie::error!("tsc exited");
+            genie::server::exit(1)
         });
 }
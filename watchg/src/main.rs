@@ -1,30 +1,33 @@
 use std::{
     collections::HashMap,
-    fs::remove_file,
-    process::Output,
+    process::{Output, Stdio},
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-use tokio::{
-    net::{UnixListener, UnixStream},
-    prelude::*,
-    process::Command,
-    stream::StreamExt,
-};
+use tokio::{net::UnixStream, process::Command};
 
 use conf::{configure, Config};
+use genie::auth::{Auth, Credential};
+use genie::server::{Parsed, Then};
 
+#[derive(Debug)]
 pub enum Request {
     Poll(GenieCookie),
     Get(GenieCookie),
-    Exit,
+    Peek(GenieCookie),
+    Latest,
+    Forget(GenieCookie),
+    Info,
+    Exit(Credential),
+    Move(Credential, String),
 }
 
 mod parse {
-    use super::{GenieCookie, Request};
+    use super::{Credential, GenieCookie, Parsed, Request};
     use nom::{
         branch::alt,
-        bytes::streaming::tag,
+        bytes::streaming::{tag, take_till1},
         character::streaming::{alphanumeric1, newline},
         IResult,
     };
@@ -53,22 +56,82 @@ mod parse {
         Ok((i, Request::Get(cookie)))
     }
 
+    fn peek_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("peek\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Peek(cookie)))
+    }
+
+    fn forget_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("forget\n")(i)?;
+        let (i, cookie) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Forget(cookie)))
+    }
+
+    fn latest_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("latest\n")(i)?;
+        Ok((i, Request::Latest))
+    }
+
+    fn info_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("info\n")(i)?;
+        Ok((i, Request::Info))
+    }
+
     fn exit_request(i: &[u8]) -> IResult<&[u8], Request> {
         let (i, _) = tag("exit\n")(i)?;
-        Ok((i, Request::Exit))
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        Ok((i, Request::Exit(Credential(credential))))
+    }
+
+    fn move_request(i: &[u8]) -> IResult<&[u8], Request> {
+        let (i, _) = tag("move\n")(i)?;
+        let (i, GenieCookie(credential)) = genie_cookie(i)?;
+        let (i, _) = newline(i)?;
+        let (i, dir) = take_till1(|c| c == b'\n')(i)?;
+        let (i, _) = newline(i)?;
+        let dir = String::from_utf8_lossy(dir).into_owned();
+        Ok((i, Request::Move(Credential(credential), dir)))
+    }
+
+    fn request(i: &[u8]) -> IResult<&[u8], Request> {
+        alt((
+            poll_request,
+            get_request,
+            peek_request,
+            latest_request,
+            forget_request,
+            info_request,
+            exit_request,
+            move_request,
+        ))(i)
     }
 
-    pub fn request(i: &[u8]) -> IResult<&[u8], Request> {
-        alt((poll_request, get_request, exit_request))(i)
+    pub fn parsed(i: &[u8]) -> Parsed<Request> {
+        match request(i) {
+            Ok((_, request)) => Parsed::Request(request),
+            Err(nom::Err::Incomplete(_)) => Parsed::Incomplete,
+            Err(_) => Parsed::Malformed,
+        }
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 pub struct GenieCookie(String);
 
+impl GenieCookie {
+    fn of(self, uid: u32) -> GenieCookie {
+        GenieCookie(genie::cookie::of_user(uid, &self.0))
+    }
+}
+
 struct WatchState {
-    fingers: HashMap<GenieCookie, (u32, Arc<Output>)>,
+    fingers: HashMap<GenieCookie, (u32, Arc<Output>, Instant)>,
     latest: Option<(u32, Arc<Output>)>,
+    started: Instant,
 }
 
 impl WatchState {
@@ -76,6 +139,7 @@ impl WatchState {
         return WatchState {
             fingers: HashMap::new(),
             latest: None,
+            started: Instant::now(),
         };
     }
 
@@ -87,9 +151,12 @@ impl WatchState {
         match &self.latest {
             None => None,
             Some((iteration, output)) => {
-                match &self.fingers.insert(cookie, (*iteration, output.clone())) {
+                match &self
+                    .fingers
+                    .insert(cookie, (*iteration, output.clone(), Instant::now()))
+                {
                     None => Some(output.clone()),
-                    Some((last_polled, _)) => {
+                    Some((last_polled, _, _)) => {
                         if iteration == last_polled {
                             None
                         } else {
@@ -107,42 +174,70 @@ impl WatchState {
                 None => None,
                 Some((_, output)) => Some(output.clone()),
             },
-            Some((_, output)) => Some(output.clone()),
+            Some((_, output, _)) => Some(output.clone()),
         }
     }
+
+    // for a client that won't be polling again
+    fn forget(&mut self, cookie: &GenieCookie) {
+        self.fingers.remove(cookie);
+    }
+
+    // what poll would show someone who has never polled
+    fn latest(&self) -> Option<Arc<Output>> {
+        self.latest.as_ref().map(|(_, output)| output.clone())
+    }
+
+    // whether poll would have something for cookie
+    fn peek(&self, cookie: &GenieCookie) -> bool {
+        match (&self.latest, self.fingers.get(cookie)) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some((iteration, _)), Some((last_polled, _, _))) => iteration != last_polled,
+        }
+    }
+
+    fn last_polled(&self) -> Instant {
+        self.fingers
+            .values()
+            .map(|(_, _, polled)| *polled)
+            .max()
+            .unwrap_or(self.started)
+    }
 }
 
 async fn send_output_to_stream(stream: &mut UnixStream, output: &Output, beep: bool) {
+    genie::trace!(
+        "responding with {} ({} bytes)",
+        output.status,
+        output.stdout.len() + output.stderr.len()
+    );
+    let mut response = Vec::new();
     if beep {
         match output.status.code() {
             Some(0) => (),
-            None | Some(_) => stream.write_all(&[0o007]).await.unwrap(),
+            None | Some(_) => response.push(0o007),
         }
     }
 
-    if output.stderr.len() > 0 {
-        stream.write_all(&output.stderr).await.unwrap()
-    }
-
-    if output.stdout.len() > 0 {
-        stream.write_all(&output.stdout).await.unwrap()
-    }
+    response.extend_from_slice(&output.stderr);
+    response.extend_from_slice(&output.stdout);
+    genie::server::reply(stream, &response).await
 }
 
 mod conf {
     use clap::{App, AppSettings, Arg};
+    use genie::server::Options;
 
     pub struct Config {
-        pub genie_dir: String,
-        pub name: String,
+        pub options: Options,
         pub command: String,
         pub interval: u64, // milliseconds
         pub beep: bool,
-        pub logfile: Option<String>,
     }
 
     pub fn configure() -> Config {
-        let matches = App::new("watchg")
+        let app = App::new("watchg")
             .setting(AppSettings::TrailingVarArg)
             .author("David L. L. Thomas <davidleothomas@gmail.com>")
             .about("execute a program periodically, make its output available as a genie")
@@ -153,22 +248,12 @@ mod conf {
                     .takes_value(true)
                     .value_name("seconds"),
             )
-            .arg(Arg::with_name("beep").short("b").long("beep"))
+            .arg(Arg::with_name("beep").short("b").long("beep"));
+        let matches = Options::watcher_args(Options::args(app))
             .arg(Arg::from_usage("<cmd>... 'command to run'"))
-            .arg(
-                Arg::with_name("logfile")
-                    .short("l")
-                    .long("logfile")
-                    .takes_value(true)
-                    .value_name("file"),
-            )
             .get_matches();
 
-        let name = "watch".to_string();
-        let path = std::env::var("GENIE_PATH")
-            .ok()
-            .expect("GENIE_PATH env var is not set");
-        let genie_dir = path.split(":").next().unwrap().to_string();
+        let options = Options::from_matches("watch", &matches);
 
         let command = matches
             .values_of("cmd")
@@ -184,134 +269,167 @@ mod conf {
 
         let beep = matches.is_present("beep");
 
-        let logfile = matches.value_of("logfile").map(String::from);
-
         Config {
-            genie_dir,
-            name,
+            options,
             command,
             interval,
             beep,
-            logfile,
         }
     }
 }
 
+// what every request is answered from
+struct Watch {
+    state: Arc<Mutex<WatchState>>,
+    auth: Auth,
+    info: Vec<u8>,
+    beep: bool,
+    name: String,
+    group: Option<u32>,
+}
+
+async fn handle(watch: Arc<Watch>, request: Request, uid: u32, mut stream: UnixStream) -> Then {
+    let state = &watch.state;
+    match request {
+        Request::Poll(cookie) => {
+            let output = state.lock().unwrap().poll(cookie.of(uid)).clone();
+            if let Some(output) = output {
+                send_output_to_stream(&mut stream, &output, watch.beep).await
+            }
+        }
+        Request::Get(cookie) => {
+            let output = state.lock().unwrap().get(cookie.of(uid)).clone();
+            if let Some(output) = output {
+                send_output_to_stream(&mut stream, &output, false).await
+            }
+        }
+        Request::Forget(cookie) => state.lock().unwrap().forget(&cookie.of(uid)),
+        Request::Latest => {
+            let output = state.lock().unwrap().latest();
+            if let Some(output) = output {
+                send_output_to_stream(&mut stream, &output, false).await
+            }
+        }
+        Request::Peek(cookie) => {
+            let unseen = state.lock().unwrap().peek(&cookie.of(uid));
+            genie::server::reply(
+                &mut stream,
+                if unseen { genie::UNSEEN } else { genie::SEEN },
+            )
+            .await
+        }
+        Request::Info => genie::server::reply(&mut stream, &watch.info).await,
+        Request::Move(credential, dir) => {
+            if !watch.auth.allows(&credential) {
+                genie::warn!("refusing unauthorized move request");
+                genie::server::reply(&mut stream, genie::auth::REFUSED).await;
+                return Then::Continue;
+            }
+            let (response, then) = match genie::server::rebind(&dir, &watch.name, watch.group) {
+                Ok((listener, socket_path)) => {
+                    genie::info!("listening at {}", socket_path.display());
+                    (
+                        format!("{}\n", socket_path.display()),
+                        Then::Listen(listener),
+                    )
+                }
+                Err(err) => {
+                    genie::warn!("unable to move to {}: {}", dir, err);
+                    (
+                        format!("{}{}\n", genie::auth::MOVE_FAILED, err),
+                        Then::Continue,
+                    )
+                }
+            };
+            genie::server::reply(&mut stream, response.as_bytes()).await;
+            return then;
+        }
+        Request::Exit(credential) => {
+            if watch.auth.allows(&credential) {
+                return Then::Exit;
+            }
+            genie::warn!("refusing unauthorized exit request");
+            genie::server::reply(&mut stream, genie::auth::REFUSED).await
+        }
+    }
+    Then::Continue
+}
+
 fn main() {
     let Config {
-        genie_dir,
-        name,
+        options,
         command,
         interval,
         beep,
-        logfile,
     } = configure();
     let state = Arc::new(Mutex::new(WatchState::new()));
 
-    let daemonize = daemonize::Daemonize::new().working_directory(".");
-
-    let daemonize = match logfile {
-        Some(logfile) => {
-            let logfile = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(logfile)
-                .expect("unable to open logfile");
-
-            let stdout = logfile.try_clone().expect("unable to clone logfile handle");
-            let stderr = logfile;
-
-            daemonize.stdout(stdout).stderr(stderr)
-        }
-        None => daemonize,
-    };
-
-    daemonize.start().expect("failed to daemonize");
-
-    let socket_path = { format!("{}/{}.{:x}.sock", genie_dir, name, std::process::id()) };
+    let started = genie::server::start(&options);
+    let info = genie::info::Info::current(&options.name, options.label.as_deref()).to_bytes();
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            {
-                let state = state.clone();
-                let mut listener = UnixListener::bind(&socket_path).unwrap();
-                tokio::spawn(async move {
-                    'top: loop {
-                        if let Some(Ok(mut stream)) = listener.next().await {
-                            let mut nbytes = 0;
-                            let mut buffer: [u8; 8192] = [0; 8192];
-                            'stream: loop {
-                                match stream.read(&mut buffer[nbytes..]).await {
-                                    Ok(0) => break,
-                                    Ok(length) => nbytes += length,
-                                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {
-                                        continue
-                                    }
-                                    Err(_err) => break 'stream,
-                                }
-
-                                loop {
-                                    match parse::request(&buffer[..nbytes]) {
-                                        Ok((_, request)) => {
-                                            match request {
-                                                Request::Poll(cookie) => {
-                                                    let output =
-                                                        state.lock().unwrap().poll(cookie).clone();
-                                                    if let Some(output) = output {
-                                                        send_output_to_stream(
-                                                            &mut stream,
-                                                            &output,
-                                                            beep,
-                                                        )
-                                                        .await
-                                                    }
-                                                }
-                                                Request::Get(cookie) => {
-                                                    let output =
-                                                        state.lock().unwrap().get(cookie).clone();
-                                                    if let Some(output) = output {
-                                                        send_output_to_stream(
-                                                            &mut stream,
-                                                            &output,
-                                                            false,
-                                                        )
-                                                        .await
-                                                    }
-                                                }
-                                                Request::Exit => break 'top,
-                                            }
-                                            break 'stream;
-                                        }
-                                        Err(nom::Err::Incomplete(_)) => continue 'stream,
-                                        Err(_) => break 'stream,
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    match remove_file(socket_path) {
-                        Err(_) => (),
-                        Ok(_) => (),
-                    }
+            let (listener, auth) = started.ready(&options);
 
-                    std::process::exit(0)
+            if let Some(idle_timeout) = options.idle_timeout {
+                let state = state.clone();
+                genie::server::exit_when_idle(Duration::from_millis(idle_timeout), move || {
+                    state.lock().unwrap().last_polled()
                 });
             }
 
+            let watch = Arc::new(Watch {
+                state: state.clone(),
+                auth,
+                info,
+                beep,
+                name: options.name.clone(),
+                group: options.group,
+            });
+            tokio::spawn(async move {
+                genie::server::serve(
+                    listener,
+                    watch.group,
+                    parse::parsed,
+                    move |request, uid, stream| handle(watch.clone(), request, uid, stream),
+                )
+                .await;
+                genie::server::exit_from_task(0).await
+            });
+
             let mut iteration = 0;
 
             loop {
-                let output: Output = Command::new("bash")
-                    .arg("-c")
-                    .arg(command.clone())
-                    .output()
-                    .await
-                    .unwrap();
-
+                let child = match genie::server::spawn_group(
+                    Command::new("bash")
+                        .arg("-c")
+                        .arg(command.clone())
+                        .stdin(Stdio::null())
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped()),
+                ) {
+                    Ok(child) => child,
+                    // as it can't once we're shutting down, when this waits
+                    // for the exit under way
+                    Err(err) => {
+                        genie::error!("unable to run command: {}", err);
+                        genie::server::exit(1)
+                    }
+                };
+                let pid = child.id();
+
+                let output: Output = child.wait_with_output().await.unwrap();
+                genie::server::forget_group(pid);
+                genie::debug!("iteration {}: {}", iteration, output.status);
+
+                let output = Output {
+                    stdout: options.redactor.apply(&output.stdout),
+                    stderr: options.redactor.apply(&output.stderr),
+                    ..output
+                };
                 state.lock().unwrap().update(iteration, &output);
 
                 std::thread::sleep(std::time::Duration::from_millis(interval));